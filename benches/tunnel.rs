@@ -0,0 +1,159 @@
+//! Measures throughput of a forwarded port and its CPU usage while idle and while the receiver is slow.
+//!
+//! Needs an ssh server to forward through, so it's skipped unless `STW_BENCH_TARGET` is set:
+//!
+//! ```
+//! STW_BENCH_TARGET=eric@localhost cargo bench --bench tunnel
+//! ```
+//!
+//! Data is sent to an echo server through the forward and read back. By default that's one started by
+//! the benchmark, which only works if the target is the local machine. Otherwise point
+//! `STW_BENCH_ECHO` at an echo server as seen from the remote, e.g. `socat tcp-l:7007,fork exec:cat`.
+//! `STW_BENCH_MIB` sets how much data to send (default 256), `STW_BENCH_IDLE` how many seconds to
+//! measure an idle connection for (default 5) and `STW_BENCH_THROTTLE` how many seconds to measure a
+//! transfer for whose receiver reads only 64 KiB every 100ms (default 5), which keeps the channel's
+//! window full.
+use std::{
+    env,
+    io::{Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+use stw::ssh::{create_session, forward::Forward, parse_address, Target};
+
+const CHUNK: usize = 65536;
+/// How long the throttled receiver waits between reads
+const THROTTLE_INTERVAL: Duration = Duration::from_millis(100);
+
+fn main() {
+    let target = match env::var("STW_BENCH_TARGET") {
+        Ok(target) => Target::parse(&target).expect("invalid STW_BENCH_TARGET"),
+        Err(_) => {
+            println!("STW_BENCH_TARGET isn't set, skipping the tunnel benchmark");
+            return;
+        },
+    };
+    let mib: usize = env_or("STW_BENCH_MIB", 256);
+    let idle = Duration::from_secs(env_or("STW_BENCH_IDLE", 5));
+    let throttle = Duration::from_secs(env_or("STW_BENCH_THROTTLE", 5));
+    let (host, port) = match env::var("STW_BENCH_ECHO") {
+        Ok(echo) => match parse_address(&echo).expect("invalid STW_BENCH_ECHO") {
+            (host, Some(port)) => (host, port),
+            (_, None) => panic!("STW_BENCH_ECHO needs a port"),
+        },
+        Err(_) => ("127.0.0.1".to_string(), echo_server()),
+    };
+
+    let session = create_session(&target).expect("couldn't connect to STW_BENCH_TARGET");
+    let forward = Forward::open(&session, &host, port).expect("couldn't open the forward");
+    let stream = TcpStream::connect(forward.local_addr()).unwrap();
+    stream.set_nodelay(true).unwrap();
+
+    let total = mib * 1024 * 1024;
+    let start = Instant::now();
+    let mut writer = stream.try_clone().unwrap();
+    let sender = thread::spawn(move || {
+        let chunk = vec![0x5a_u8; CHUNK];
+        let mut sent = 0;
+        while sent < total {
+            let amount = CHUNK.min(total - sent);
+            writer.write_all(&chunk[..amount]).unwrap();
+            sent += amount;
+        }
+    });
+    let mut reader = stream.try_clone().unwrap();
+    let mut buf = vec![0_u8; CHUNK];
+    let mut received = 0;
+    while received < total {
+        match reader.read(&mut buf).unwrap() {
+            0 => panic!("connection closed after {} of {} bytes", received, total),
+            amount => received += amount,
+        }
+    }
+    sender.join().unwrap();
+    let elapsed = start.elapsed();
+    println!(
+        "throughput: {} MiB each way in {:.2?}, {:.1} MiB/s",
+        mib,
+        elapsed,
+        mib as f64 / elapsed.as_secs_f64()
+    );
+
+    // the connection stays open, so the relay has something to watch while it's idle
+    let cpu_before = cpu_time();
+    thread::sleep(idle);
+    report_cpu("idle cpu", cpu_before, cpu_time(), idle);
+
+    // the sender keeps going until the connection is shut down, so the relay is always waiting for the
+    // receiver to catch up
+    let mut writer = stream.try_clone().unwrap();
+    let sender = thread::spawn(move || {
+        let chunk = vec![0x5a_u8; CHUNK];
+        while writer.write_all(&chunk).is_ok() {}
+    });
+    let cpu_before = cpu_time();
+    let start = Instant::now();
+    while start.elapsed() < throttle {
+        thread::sleep(THROTTLE_INTERVAL);
+        if reader.read(&mut buf).unwrap() == 0 {
+            panic!("connection closed during the throttled transfer");
+        }
+    }
+    report_cpu("throttled cpu", cpu_before, cpu_time(), start.elapsed());
+    stream.shutdown(Shutdown::Both).unwrap();
+    sender.join().unwrap();
+}
+
+/// Print the CPU time used between two `cpu_time` readings taken `over` apart.
+fn report_cpu(label: &str, before: Option<Duration>, after: Option<Duration>, over: Duration) {
+    match (before, after) {
+        (Some(before), Some(after)) => println!(
+            "{}: {:.2?} over {:.2?}, {:.2}% of a core",
+            label,
+            after - before,
+            over,
+            (after - before).as_secs_f64() / over.as_secs_f64() * 100.0
+        ),
+        _ => println!("{}: not measured on this platform", label),
+    }
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    env::var(name).ok().and_then(|x| x.parse().ok()).unwrap_or(default)
+}
+
+/// Start an echo server on a free loopback port and return the port.
+fn echo_server() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            thread::spawn(move || {
+                let mut reader = stream.try_clone().unwrap();
+                let mut writer = stream;
+                let _ = std::io::copy(&mut reader, &mut writer);
+            });
+        }
+    });
+    port
+}
+
+/// User and system time used by this process so far.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn cpu_time() -> Option<Duration> {
+    // SAFETY: getrusage only writes to the struct it's given
+    let usage = unsafe {
+        let mut usage = std::mem::zeroed::<libc::rusage>();
+        if libc::getrusage(libc::RUSAGE_SELF, &mut usage) != 0 {
+            return None;
+        }
+        usage
+    };
+    let time = |x: libc::timeval| Duration::new(x.tv_sec as u64, x.tv_usec as u32 * 1000);
+    Some(time(usage.ru_utime) + time(usage.ru_stime))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn cpu_time() -> Option<Duration> { None }
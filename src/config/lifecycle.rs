@@ -0,0 +1,596 @@
+use askama::Template;
+use gethostname::gethostname;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use ssh2::{self, Channel, Listener, Session};
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::{
+    fs::{self, File},
+    io::Write,
+    net::{SocketAddr, TcpListener},
+    ops::Deref,
+};
+use typed_path::{PathBuf, UnixEncoding};
+
+use crate::{
+    config::{Conf, ConfError, KeyPair},
+    ssh::{config::expand_home, create_session, download, exec, forward::Forward, relay::Relay, remote_sha256, upload},
+    st::{
+        api::Client,
+        binary::{bundled_sha256, extract_binary, sha256_hex, syncthing_binary, Platform, SYNCTHING_VERSION},
+        config::{self, generate_api_key, generate_password, ConfigTemplate},
+        deviceid::get_device_id,
+        process::{find_in_path, Supervisor},
+    },
+};
+
+/// REST API client for the remote syncthing, forwarding over ssh for as long as it's alive.
+pub struct RemoteApi {
+    client: Client,
+    _forward: Forward,
+}
+
+impl Deref for RemoteApi {
+    type Target = Client;
+
+    fn deref(&self) -> &Client { &self.client }
+}
+
+/// Configs rendered for the current session, saved next to the local config.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct SessionState {
+    local_config: ConfigTemplate,
+    remote_config: ConfigTemplate,
+}
+
+/// Owner-only permissions for keys and configs, which hold the GUI password hash and API key.
+const PRIVATE_FILE_MODE: u32 = 0o600;
+/// Owner-only permissions for the config folders.
+const PRIVATE_FOLDER_MODE: u32 = 0o700;
+
+/// Local side of a session, created by `Conf::prepare_local`.
+#[derive(Clone, Debug)]
+pub struct LocalSetup {
+    pub config_folder: std::path::PathBuf,
+    pub hostname: String,
+    pub device_id: String,
+}
+
+/// Remote side of a session, created by `Conf::provision_remote`.
+#[derive(Clone, Debug)]
+pub struct RemoteSetup {
+    pub config_folder: PathBuf<UnixEncoding>,
+    pub data_folder: PathBuf<UnixEncoding>,
+    pub syncthing_path: PathBuf<UnixEncoding>,
+    pub platform: Platform,
+    pub hostname: String,
+    pub device_id: String,
+}
+
+/// Web ui login shared by both syncthing instances, created by `Conf::write_configs`.
+#[derive(Clone, Debug)]
+pub struct GuiCredentials {
+    pub user: String,
+    pub password: String,
+}
+
+/// Listeners for both directions of the syncthing tunnel, created by `Conf::open_tunnels`.
+pub struct Tunnels {
+    remote_listener: Listener,
+    local_listener: TcpListener,
+}
+
+impl Conf {
+    /// Open an authenticated ssh session to the remote.
+    pub fn connect(&self) -> Result<Session, ConfError> { Ok(create_session(&self.ssh_target()?)?) }
+
+    /// Create the local config folder and keypair.
+    pub fn prepare_local(&self, rotate_keys: bool) -> Result<LocalSetup, ConfError> {
+        let config_folder = self.local_config_folder()?;
+        create_private_folder(&config_folder)?;
+
+        let existing = match rotate_keys {
+            true => None,
+            false => existing_keypair(
+                "local",
+                fs::read(config_folder.join("key.pem")).ok(),
+                fs::read(config_folder.join("cert.pem")).ok(),
+            )?,
+        };
+        let keypair = match existing {
+            Some(keypair) => keypair,
+            None => {
+                println!("Generating local keypair");
+                let keypair = self.new_keypair()?;
+                write_private(&config_folder.join("key.pem"), keypair.key.as_bytes())?;
+                let mut cert = File::create(config_folder.join("cert.pem"))?;
+                cert.write_all(keypair.cert.as_bytes())?;
+                keypair
+            },
+        };
+        restrict_permissions(&config_folder.join("key.pem"), PRIVATE_FILE_MODE)?;
+        let device_id = get_device_id(&keypair.cert)?;
+
+        Ok(LocalSetup {
+            config_folder,
+            hostname: gethostname().to_string_lossy().to_string(),
+            device_id,
+        })
+    }
+
+    /// Create the remote config and data folders, upload syncthing and create the remote keypair if it
+    /// doesn't have one yet.
+    pub fn provision_remote(&self, session: &Session, rotate_keys: bool) -> Result<RemoteSetup, ConfError> {
+        println!("Creating remote config folder");
+        let (config_folder, data_folder) = self.remote_folders(session)?;
+        let (hostname, _) = exec(session, "hostname")?;
+
+        for folder in [&config_folder, &data_folder] {
+            let (_, code) = exec(session, &format!("mkdir -p {:#?}", folder.as_path().to_string_lossy()))?;
+            if code != 0 {
+                return Err(ConfError::RemoteFolder);
+            }
+        }
+        restrict_remote(session, &config_folder, PRIVATE_FOLDER_MODE)?;
+
+        let (uname, _) = exec(session, "uname -sm")?;
+        let platform = Platform::from_uname(&uname)?;
+        println!("Remote platform is {}", platform);
+
+        let syncthing_path = data_folder.join("syncthing");
+        self.upload_syncthing(session, &platform, &syncthing_path)?;
+
+        let key_path = unix_to_std(&config_folder.join("key.pem"));
+        let cert_path = unix_to_std(&config_folder.join("cert.pem"));
+        let existing = match rotate_keys {
+            true => None,
+            false => existing_keypair("remote", download(session, &key_path)?, download(session, &cert_path)?)?,
+        };
+        let keypair = match existing {
+            Some(keypair) => keypair,
+            None => {
+                println!("Uploading new keys to remote");
+                let keypair = self.new_keypair()?;
+                upload(session, &key_path, PRIVATE_FILE_MODE as i32, keypair.key.as_bytes())?;
+                upload(session, &cert_path, 0o644, keypair.cert.as_bytes())?;
+                keypair
+            },
+        };
+        // scp keeps the mode of files that already exist
+        restrict_remote(session, &config_folder.join("key.pem"), PRIVATE_FILE_MODE)?;
+        let device_id = get_device_id(&keypair.cert)?;
+
+        Ok(RemoteSetup {
+            config_folder,
+            data_folder,
+            syncthing_path,
+            platform,
+            hostname: hostname.trim().into(),
+            device_id,
+        })
+    }
+
+    /// Upload syncthing unless the remote already has the same binary, then check the uploaded copy and
+    /// its version.
+    fn upload_syncthing(
+        &self,
+        session: &Session,
+        platform: &Platform,
+        syncthing_path: &PathBuf<UnixEncoding>,
+    ) -> Result<(), ConfError> {
+        let remote_path = syncthing_path.as_path().to_string_lossy().to_string();
+        let remote_hash = remote_sha256(session, &remote_path)?;
+        // the manifest saves decompressing the bundled binary when the remote is up to date
+        let bundled = self.syncthing_binary.is_none() && self.syncthing_version() == SYNCTHING_VERSION;
+        if bundled && remote_hash.is_some() && remote_hash == bundled_sha256(platform) {
+            println!("Remote syncthing is up to date");
+            return Ok(());
+        }
+        let syncthing_binary = self.load_syncthing(platform)?;
+        let hash = sha256_hex(&syncthing_binary);
+        if remote_hash.as_deref() == Some(hash.as_str()) {
+            println!("Remote syncthing is up to date");
+        } else {
+            println!("Uploading syncthing to remote");
+            upload(session, &unix_to_std(syncthing_path), 0o755, &syncthing_binary)?;
+            match remote_sha256(session, &remote_path)? {
+                Some(actual) if actual != hash => return Err(ConfError::RemoteChecksum { expected: hash, actual }),
+                Some(_) => {},
+                None => warn!("Couldn't verify syncthing on the remote, sha256sum and shasum are missing"),
+            }
+        }
+
+        if self.syncthing_version.is_some() {
+            let (version, _) = exec(session, &format!("{:#?} --version", remote_path))?;
+            if !version.split_whitespace().any(|x| x == self.syncthing_version()) {
+                warn!(
+                    "Expected syncthing {} on the remote, got `{}`",
+                    self.syncthing_version(),
+                    version.trim()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the syncthing binary for `platform`, from `syncthing_binary` if it's set.
+    fn load_syncthing(&self, platform: &Platform) -> Result<Vec<u8>, ConfError> {
+        match &self.syncthing_binary {
+            Some(path) => {
+                let path = std::path::PathBuf::from(expand_home(path));
+                if let Some(binary_platform) = self.syncthing_binary_platform() {
+                    if binary_platform != *platform {
+                        return Err(ConfError::PlatformMismatch {
+                            binary: binary_platform.to_string(),
+                            remote: platform.to_string(),
+                        });
+                    }
+                }
+                Ok(extract_binary(&path)?)
+            },
+            None => Ok(syncthing_binary(
+                platform,
+                &self.syncthing_version(),
+                &self.cache_folder()?,
+            )?),
+        }
+    }
+
+    /// Find the syncthing executable to run locally: `local_syncthing`, then `syncthing_binary` if it's
+    /// built for this machine, then `syncthing` in `PATH`, then the bundled or cached binary for this
+    /// machine. Binaries from archives are extracted to the local data folder.
+    pub fn find_local_syncthing(&self, remote: &RemoteSetup) -> Result<std::path::PathBuf, ConfError> {
+        if let Some(path) = &self.local_syncthing {
+            return Ok(expand_home(path).into());
+        }
+        if self.syncthing_binary.is_some() {
+            let binary_platform = self
+                .syncthing_binary_platform()
+                .unwrap_or_else(|| remote.platform.clone());
+            if Platform::local().ok().as_ref() == Some(&binary_platform) {
+                let binary = self.load_syncthing(&binary_platform)?;
+                return self.install_local_syncthing(&binary_platform, &binary);
+            }
+        }
+        // a syncthing in PATH works on platforms stw has no builds for
+        if let Some(path) = find_in_path() {
+            return Ok(path);
+        }
+        let local_platform = Platform::local()?;
+        let binary = syncthing_binary(&local_platform, &self.syncthing_version(), &self.cache_folder()?)?;
+        self.install_local_syncthing(&local_platform, &binary)
+    }
+
+    /// Write a syncthing binary to the local data folder, unless it's already there.
+    fn install_local_syncthing(&self, platform: &Platform, binary: &[u8]) -> Result<std::path::PathBuf, ConfError> {
+        let data_folder = self.data_folder()?;
+        fs::create_dir_all(&data_folder)?;
+        let mut name = platform.binary_name(&self.syncthing_version());
+        if cfg!(windows) {
+            name.push_str(".exe");
+        }
+        let path = data_folder.join(name);
+        let up_to_date = match fs::read(&path) {
+            Ok(existing) => sha256_hex(&existing) == sha256_hex(binary),
+            Err(_) => false,
+        };
+        if !up_to_date {
+            fs::write(&path, binary)?;
+            #[cfg(unix)]
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+        }
+        Ok(path)
+    }
+
+    /// Platform of `syncthing_binary` if its file name follows the syncthing release naming.
+    fn syncthing_binary_platform(&self) -> Option<Platform> {
+        let path = std::path::PathBuf::from(self.syncthing_binary.as_ref()?);
+        Platform::from_file_name(&path.file_name()?.to_string_lossy())
+    }
+
+    /// Render and write the syncthing config for both sides.
+    pub fn write_configs(
+        &mut self,
+        session: &Session,
+        local: &LocalSetup,
+        remote: &RemoteSetup,
+    ) -> Result<GuiCredentials, ConfError> {
+        // generate web ui password
+        let password = generate_password()?;
+
+        let local_config = ConfigTemplate {
+            local_device_id: local.device_id.clone(),
+            local_device_name: local.hostname.clone(),
+            remote_device_id: remote.device_id.clone(),
+            remote_device_name: remote.hostname.clone(),
+            gui_password: password.1.clone(),
+            api_key: generate_api_key(),
+            gui_port: self.gui_port(),
+            listen_port: self.listen_port(),
+            tunnel_port: self.tunnel_port(),
+            folders: self
+                .folders
+                .iter()
+                .map(|x| config::Folder {
+                    id: x.get_id(),
+                    path: x.local_path.clone(),
+                })
+                .collect(),
+        };
+        write_private(
+            &local.config_folder.join("config.xml"),
+            local_config.render()?.as_bytes(),
+        )?;
+
+        let remote_config = ConfigTemplate {
+            local_device_id: remote.device_id.clone(),
+            local_device_name: remote.hostname.clone(),
+            remote_device_id: local.device_id.clone(),
+            remote_device_name: local.hostname.clone(),
+            gui_password: password.1,
+            api_key: generate_api_key(),
+            gui_port: self.gui_port(),
+            listen_port: self.listen_port(),
+            tunnel_port: self.tunnel_port(),
+            folders: self
+                .folders
+                .iter()
+                .map(|x| config::Folder {
+                    id: x.get_id(),
+                    path: x.remote_path.clone(),
+                })
+                .collect(),
+        };
+        println!("Uploading config to remote");
+        let remote_config_path = remote.config_folder.join("config.xml");
+        upload(
+            session,
+            &unix_to_std(&remote_config_path),
+            PRIVATE_FILE_MODE as i32,
+            remote_config.render()?.as_bytes(),
+        )?;
+        restrict_remote(session, &remote_config_path, PRIVATE_FILE_MODE)?;
+
+        self.local_config = Some(local_config);
+        self.remote_config = Some(remote_config);
+        self.save_state()?;
+        Ok(GuiCredentials {
+            user: "stw".into(),
+            password: password.0,
+        })
+    }
+
+    /// Save the rendered configs to `state.yml` in the local config folder, so later commands can find
+    /// the API keys.
+    fn save_state(&self) -> Result<(), ConfError> {
+        let state = SessionState {
+            local_config: self.local_config.clone().ok_or(ConfError::NotInitialized)?,
+            remote_config: self.remote_config.clone().ok_or(ConfError::NotInitialized)?,
+        };
+        write_private(
+            &self.local_config_folder()?.join("state.yml"),
+            serde_yaml::to_string(&state)?.as_bytes(),
+        )
+    }
+
+    /// Load the configs saved by the last `init` or `up`.
+    pub fn load_state(&mut self) -> Result<(), ConfError> {
+        let path = self.local_config_folder()?.join("state.yml");
+        if !path.exists() {
+            return Err(ConfError::NotInitialized);
+        }
+        let state: SessionState = serde_yaml::from_str(&fs::read_to_string(path)?)?;
+        self.local_config = Some(state.local_config);
+        self.remote_config = Some(state.remote_config);
+        Ok(())
+    }
+
+    /// REST API client for the local syncthing.
+    pub fn local_api(&mut self) -> Result<Client, ConfError> {
+        let (api_key, gui_port) = self.api_access(|x| &x.local_config)?;
+        Ok(Client::new(&format!("http://127.0.0.1:{}", gui_port), &api_key))
+    }
+
+    /// REST API client for the remote syncthing, through an ssh forward to its GUI port.
+    pub fn remote_api(&mut self, session: &Session) -> Result<RemoteApi, ConfError> {
+        let (api_key, gui_port) = self.api_access(|x| &x.remote_config)?;
+        let forward = Forward::open(session, "127.0.0.1", gui_port)?;
+        Ok(RemoteApi {
+            client: Client::new(&format!("http://{}", forward.local_addr()), &api_key),
+            _forward: forward,
+        })
+    }
+
+    /// API key and GUI port from the current configs, loading `state.yml` if they haven't been generated
+    /// in this run.
+    fn api_access(&mut self, side: fn(&Conf) -> &Option<ConfigTemplate>) -> Result<(String, u16), ConfError> {
+        if side(self).is_none() {
+            self.load_state()?;
+        }
+        side(self)
+            .as_ref()
+            .map(|x| (x.api_key.clone(), x.gui_port))
+            .ok_or(ConfError::NotInitialized)
+    }
+
+    /// Make sure nothing is listening on the syncthing ports yet, on either side. The tunnel port is
+    /// checked by `open_tunnels`.
+    pub fn check_ports(&self, session: &Session) -> Result<(), ConfError> {
+        let ports = [(self.gui_port(), "gui_port"), (self.listen_port(), "listen_port")];
+        for (port, setting) in ports {
+            if let Err(e) = TcpListener::bind(("127.0.0.1", port)) {
+                debug!("can't bind local port {}: {}", port, e);
+                return Err(ConfError::PortInUse {
+                    side: "local",
+                    port,
+                    setting,
+                });
+            }
+            // sshd only opens the channel if it can connect
+            if let Ok(mut channel) = session.channel_direct_tcpip("127.0.0.1", port, None) {
+                let _ = channel.close();
+                return Err(ConfError::PortInUse {
+                    side: "remote",
+                    port,
+                    setting,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Listen on both ends of the syncthing tunnel.
+    pub fn open_tunnels(&self, session: &Session) -> Result<Tunnels, ConfError> {
+        let port = self.tunnel_port();
+        let in_use = |side| ConfError::PortInUse {
+            side,
+            port,
+            setting: "tunnel_port",
+        };
+
+        // create remote port forward
+        let (remote_listener, _) = session.channel_forward_listen(port, None, None).map_err(|e| {
+            debug!("can't listen on remote port {}: {}", port, e);
+            in_use("remote")
+        })?;
+
+        // create local listener to forward
+        let local_listener = TcpListener::bind(("127.0.0.1", port)).map_err(|e| {
+            debug!("can't bind local port {}: {}", port, e);
+            in_use("local")
+        })?;
+
+        Ok(Tunnels {
+            remote_listener,
+            local_listener,
+        })
+    }
+
+    /// Start syncthing on the remote. It runs for as long as the returned channel is open.
+    pub fn launch_remote(&self, session: &Session, remote: &RemoteSetup) -> Result<Channel, ConfError> {
+        let mut channel = session.channel_session()?;
+        channel.request_pty("xterm", None, None)?;
+        channel.exec(&format!(
+            "{:#?} serve --home={:#?}",
+            remote.syncthing_path.as_path().to_string_lossy(),
+            remote.config_folder.as_path().to_string_lossy()
+        ))?;
+        Ok(channel)
+    }
+
+    /// Start the local syncthing and keep it running until the returned supervisor is stopped.
+    pub fn launch_local(&self, local: &LocalSetup, binary: &std::path::Path) -> Result<Supervisor, ConfError> {
+        let supervisor = Supervisor::start(binary, &local.config_folder)?;
+        println!(
+            "Local syncthing started, logging to {:#?}",
+            local.config_folder.join("syncthing.log")
+        );
+        Ok(supervisor)
+    }
+
+    /// Forward traffic between the local and remote syncthing instances. Only returns on error.
+    pub fn run_tunnels(&self, session: &Session, tunnels: Tunnels) -> Result<(), ConfError> {
+        let Tunnels {
+            remote_listener,
+            local_listener,
+        } = tunnels;
+
+        let listen_port = self.listen_port();
+        let mut relay = Relay::new(session)?;
+        // remote syncthing connecting to us, and the local one connecting to the remote
+        relay.listen_remote(remote_listener, SocketAddr::from(([127, 0, 0, 1], listen_port)));
+        relay.listen_local(local_listener, "127.0.0.1", listen_port)?;
+        Ok(relay.run()?)
+    }
+}
+
+/// Create a folder only the owner can access, tightening the permissions of an existing one.
+fn create_private_folder(path: &std::path::Path) -> Result<(), ConfError> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    builder.mode(PRIVATE_FOLDER_MODE);
+    builder.create(path)?;
+    restrict_permissions(path, PRIVATE_FOLDER_MODE)
+}
+
+/// Write a file only the owner can read, tightening the permissions of an existing one.
+fn write_private(path: &std::path::Path, data: &[u8]) -> Result<(), ConfError> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(PRIVATE_FILE_MODE);
+    // tighten before writing so the contents are never readable by others
+    let mut file = options.open(path)?;
+    restrict_permissions(path, PRIVATE_FILE_MODE)?;
+    file.write_all(data)?;
+    Ok(())
+}
+
+/// Remove any permissions beyond `mode` from a local file or folder, with a warning.
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn restrict_permissions(path: &std::path::Path, mode: u32) -> Result<(), ConfError> {
+    #[cfg(unix)]
+    {
+        let current = fs::metadata(path)?.permissions().mode() & 0o777;
+        if current & !mode != 0 {
+            warn!(
+                "{:#?} had permissions {:o}, changing them to {:o}",
+                path,
+                current,
+                current & mode
+            );
+            fs::set_permissions(path, fs::Permissions::from_mode(current & mode))?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove any permissions beyond `mode` from a remote file or folder, with a warning.
+fn restrict_remote(session: &Session, path: &PathBuf<UnixEncoding>, mode: u32) -> Result<(), ConfError> {
+    let path = path.as_path().to_string_lossy();
+    // GNU stat, then BSD stat
+    let (output, code) = exec(
+        session,
+        &format!("stat -c %a {0:#?} 2>/dev/null || stat -f %Lp {0:#?}", path),
+    )?;
+    let current = match (code, u32::from_str_radix(output.trim(), 8)) {
+        (0, Ok(current)) => current,
+        _ => {
+            warn!("Couldn't read the permissions of {:#?} on the remote", path);
+            return Ok(());
+        },
+    };
+    if current & !mode != 0 {
+        warn!(
+            "{:#?} on the remote had permissions {:o}, changing them to {:o}",
+            path,
+            current,
+            current & mode
+        );
+        exec(session, &format!("chmod {:o} {:#?}", current & mode, path))?;
+    }
+    Ok(())
+}
+
+/// Keypair from a previous run, if both files are there. A lone key or cert can't be used, so it's
+/// replaced.
+fn existing_keypair(side: &str, key: Option<Vec<u8>>, cert: Option<Vec<u8>>) -> Result<Option<KeyPair>, ConfError> {
+    match (key, cert) {
+        (Some(key), Some(cert)) => {
+            debug!("reusing {} keypair", side);
+            Ok(Some(KeyPair::from_pem(&key, &cert)?))
+        },
+        (None, None) => Ok(None),
+        _ => {
+            warn!("Incomplete {} keypair, generating a new one", side);
+            Ok(None)
+        },
+    }
+}
+
+/// scp takes a std path, convert a remote unix path for it.
+pub(crate) fn unix_to_std(path: &PathBuf<UnixEncoding>) -> std::path::PathBuf {
+    std::path::PathBuf::from(path.as_path().to_string_lossy().to_string())
+}
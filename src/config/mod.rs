@@ -1,42 +1,44 @@
-use askama::Template;
 use bcrypt::BcryptError;
 use directories::ProjectDirs;
 use gethostname::gethostname;
-use md5;
+use log::warn;
 use openssl::{
     asn1::Asn1Time,
     bn::{BigNum, MsbOption},
     ec::{EcGroup, EcKey},
     hash::MessageDigest,
     nid::Nid,
-    pkey::PKey,
+    pkey::{PKey, Private},
+    rsa::Rsa,
     x509::{X509Extension, X509},
 };
 use serde::{Deserialize, Serialize};
-use ssh2;
+use ssh2::{self, Channel, Session};
 use std::{
-    env,
-    fs::{self, File},
-    include_bytes,
-    io::{BufReader, BufWriter, Read, Write},
-    net::{Shutdown, TcpListener, TcpStream},
+    env, fs,
     path::Path,
-    sync::Mutex,
-    thread,
-    time::Duration,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 use thiserror::Error;
-use typed_path::{PathBuf, UnixEncoding};
+use typed_path::{Component, PathBuf, UnixEncoding};
 
 use crate::{
-    ssh::{create_session, SshError},
+    ssh::{
+        config::expand_home, exec, known_hosts::StrictHostKeyChecking, parse_address, run_pty,
+        shell::interactive_shell, shell_quote, SshError, Target,
+    },
     st::{
-        config::{self, generate_password, ConfigTemplate},
-        deviceid::get_device_id,
+        api::Client,
+        binary::SYNCTHING_VERSION,
+        config::{ConfigTemplate, DEFAULT_GUI_PORT, DEFAULT_LISTEN_PORT, DEFAULT_TUNNEL_PORT},
+        process::Supervisor,
     },
-    CHANNEL,
 };
 
+/// Lifecycle phases of a session
+pub mod lifecycle;
+
 #[derive(Error, Debug)]
 pub enum ConfError {
     #[error("Error parsing config to yaml")]
@@ -57,16 +59,59 @@ pub enum ConfError {
     NotFound,
     #[error("Couldn't create remote directory")]
     RemoteFolder,
-    #[error("Couldn't set channel")]
-    Channel,
+    #[error("{0:#?} isn't in any of the synced folders")]
+    NotInFolder(std::path::PathBuf),
+    #[error("Folders weren't in sync after {0:?}")]
+    Timeout(Duration),
+    #[error("Session isn't initialized, run `stw init` or `stw up` first")]
+    NotInitialized,
+    #[error("openssl error")]
+    Ssl(#[from] openssl::error::ErrorStack),
+    #[error("The device key doesn't match its certificate, run with --rotate-keys to replace them")]
+    KeyMismatch,
+    #[error("syncthing_binary is built for {binary} but the remote is {remote}")]
+    PlatformMismatch { binary: String, remote: String },
+    #[error("syncthing on the remote doesn't match after upload, expected {expected} but got {actual}")]
+    RemoteChecksum { expected: String, actual: String },
+    #[error("Port {port} is already in use on the {side} machine, set `{setting}` to use another one")]
+    PortInUse {
+        side: &'static str,
+        port: u16,
+        setting: &'static str,
+    },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct Conf {
+    /// `host`, `host:port`, `[ipv6]:port` or a `~/.ssh/config` alias
     pub remote_address: String,
-    pub remote_user: String,
+    /// ssh port, used when `remote_address` doesn't include one. Defaults to `~/.ssh/config`, then 22.
+    pub remote_port: Option<u16>,
+    /// Defaults to `~/.ssh/config`, then the local user name.
+    pub remote_user: Option<String>,
     pub ssh_key: Option<String>,
+    /// `yes`, `no`, `accept-new` or `ask`. Defaults to `~/.ssh/config`, then `ask`.
+    pub strict_host_key_checking: Option<StrictHostKeyChecking>,
+    /// Jump hosts to reach the remote through, as `[user@]host[:port]`. Defaults to `~/.ssh/config` `ProxyJump`.
+    pub jump_hosts: Option<Vec<String>>,
+    /// syncthing binary or release archive (`.tar.gz`, `.tar.xz`, `.xz`) to use instead of the bundled one
+    pub syncthing_binary: Option<String>,
+    /// syncthing version to use, e.g. `v1.23.0`. Defaults to the bundled version.
+    pub syncthing_version: Option<String>,
+    /// syncthing executable to run locally. Defaults to `syncthing_binary` if it's built for this
+    /// machine, then `syncthing` in `PATH`, then the bundled binary.
+    pub local_syncthing: Option<String>,
+    /// `ed25519`, `p-256`, `p-384` or `rsa-3072`, for newly generated device keys. Defaults to `p-384`.
+    pub key_algorithm: Option<KeyAlgorithm>,
+    /// Validity of newly generated device certificates in days. Defaults to 3650.
+    pub key_validity_days: Option<u32>,
+    /// syncthing GUI and REST API port, on both sides. Defaults to 8384.
+    pub gui_port: Option<u16>,
+    /// Port syncthing listens for the other device on, on both sides. Defaults to 22000.
+    pub listen_port: Option<u16>,
+    /// Port the ssh tunnel listens on, on both sides. Defaults to 22001.
+    pub tunnel_port: Option<u16>,
     pub folders: Vec<Folder>,
     pub local_config: Option<ConfigTemplate>,
     pub remote_config: Option<ConfigTemplate>,
@@ -74,341 +119,469 @@ pub struct Conf {
 
 impl Conf {
     pub fn get_folder(&self) -> String {
-        let digest = md5::compute(format!(
-            "{}{}",
-            &self.remote_address,
-            gethostname().to_string_lossy().to_string()
-        ));
+        let digest = md5::compute(format!("{}{}", &self.remote_address, gethostname().to_string_lossy()));
         format!("{:x}", digest)
     }
 
-    pub fn generate_config_templates(&mut self) -> Result<(), ConfError> {
-        // Find local config folder for session
-        let config_folder = self.get_folder();
-        let local_config_folder = match ProjectDirs::from("com", "etromb", "stw") {
-            Some(proj_dirs) => {
-                let conf_dir = proj_dirs.config_dir();
-                let conf_dir = conf_dir.join(&config_folder);
-                Ok(conf_dir)
-            },
+    /// ssh connection target for the remote, before `~/.ssh/config` is applied.
+    pub fn ssh_target(&self) -> Result<Target, ConfError> {
+        let (host, port) = parse_address(&self.remote_address)?;
+        Ok(Target {
+            host,
+            port: port.or(self.remote_port),
+            user: self.remote_user.clone(),
+            key: self.ssh_key.as_ref().map(|x| expand_home(x).into()),
+            strict_host_key_checking: self.strict_host_key_checking,
+            jump_hosts: self.jump_hosts.clone().unwrap_or_default(),
+        })
+    }
+
+    /// Local folder holding the syncthing config for this session.
+    pub fn local_config_folder(&self) -> Result<std::path::PathBuf, ConfError> {
+        match ProjectDirs::from("com", "etromb", "stw") {
+            Some(proj_dirs) => Ok(proj_dirs.config_dir().join(self.get_folder())),
             None => Err(ConfError::NotFound),
-        }?;
-
-        // Verify connectivity and find config folder for remote session
-        let session = create_session(&self.remote_address, &self.remote_user, self.ssh_key.as_ref())?;
-
-        println!("Creating remote config folder");
-        let mut channel = session.channel_session()?;
-        channel.exec("eval echo ~$USER")?;
-        let mut s = String::new();
-        channel.read_to_string(&mut s)?;
-        channel.wait_close()?;
-        let remote_config_folder = PathBuf::<UnixEncoding>::from(&s.trim())
-            .join(".config/stw/")
-            .join(&config_folder);
-        let remote_data_folder = PathBuf::<UnixEncoding>::from(&s.trim()).join(".local/share/stw/");
-        let mut channel = session.channel_session()?;
-        channel.exec("hostname")?;
-        let mut s = String::new();
-        channel.read_to_string(&mut s)?;
-        channel.wait_close()?;
-        let remote_hostname = s.trim();
-
-        // Create local config folder
-        if !local_config_folder.exists() {
-            fs::create_dir_all(&local_config_folder)?;
         }
+    }
 
-        // Create local keypairs
-        let local_hostname = gethostname().to_string_lossy().to_string();
+    /// Generate a device keypair with the configured algorithm and validity.
+    pub fn new_keypair(&self) -> Result<KeyPair, ConfError> {
         // set cn to syncthing instead of hostname
-        let local_keypair = KeyPair::new("syncthing");
-        let local_key_path = local_config_folder.join("key.pem");
-        let local_cert_path = local_config_folder.join("cert.pem");
-        {
-            let mut local_key = File::create(&local_key_path)?;
-            local_key.write_all(local_keypair.key.as_bytes())?;
-            let mut local_cert = File::create(&local_cert_path)?;
-            local_cert.write_all(local_keypair.cert.as_bytes())?;
-        }
-        let local_device_id = get_device_id(&local_keypair.cert)?;
-
-        // Create remote config folder
-        let mut channel = session.channel_session()?;
-        channel.exec(&format!(
-            "mkdir -p {:#?}",
-            remote_config_folder.as_path().to_string_lossy()
-        ))?;
-        let mut s = String::new();
-        channel.read_to_string(&mut s)?;
-        channel.wait_close()?;
-        if channel.exit_status()? != 0 {
-            return Err(ConfError::RemoteFolder);
-        }
+        KeyPair::new(
+            "syncthing",
+            self.key_algorithm.unwrap_or_default(),
+            self.key_validity_days.unwrap_or(DEFAULT_KEY_VALIDITY_DAYS),
+        )
+    }
 
-        // Create remote data folder
-        let mut channel = session.channel_session()?;
-        channel.exec(&format!(
-            "mkdir -p {:#?}",
-            remote_data_folder.as_path().to_string_lossy()
-        ))?;
-        let mut s = String::new();
-        channel.read_to_string(&mut s)?;
-        channel.wait_close()?;
-        if channel.exit_status()? != 0 {
-            return Err(ConfError::RemoteFolder);
+    /// syncthing version to use, with a leading `v` like the release names.
+    pub fn syncthing_version(&self) -> String {
+        match &self.syncthing_version {
+            Some(version) => format!("v{}", version.trim_start_matches('v')),
+            None => SYNCTHING_VERSION.into(),
         }
+    }
 
-        println!("Uploading syncthing to remote");
-        // Upload syncthing to remote
-        let remote_syncthing_path = remote_data_folder.join("syncthing");
-        let remote_syncthing_path = Path::new(remote_syncthing_path.as_path().to_str().unwrap());
-        let syncthing_binary_compressed = include_bytes!("../../resources/syncthing-linux-amd64-v1.21.0.xz");
-        let mut f = std::io::Cursor::new(syncthing_binary_compressed);
-        let mut syncthing_binary = Vec::new();
-        lzma_rs::xz_decompress(&mut f, &mut syncthing_binary).unwrap();
-        let mut sent = 0;
-        let size = syncthing_binary.len();
-        let mut remote_syncthing = session.scp_send(remote_syncthing_path, 0o755, size as u64, None)?;
-        while sent < size {
-            sent += remote_syncthing.write(&syncthing_binary[sent..])?;
+    pub fn gui_port(&self) -> u16 { self.gui_port.unwrap_or(DEFAULT_GUI_PORT) }
+
+    pub fn listen_port(&self) -> u16 { self.listen_port.unwrap_or(DEFAULT_LISTEN_PORT) }
+
+    pub fn tunnel_port(&self) -> u16 { self.tunnel_port.unwrap_or(DEFAULT_TUNNEL_PORT) }
+
+    /// Local folder syncthing binaries are extracted to.
+    pub fn data_folder(&self) -> Result<std::path::PathBuf, ConfError> {
+        match ProjectDirs::from("com", "etromb", "stw") {
+            Some(proj_dirs) => Ok(proj_dirs.data_dir().to_path_buf()),
+            None => Err(ConfError::NotFound),
         }
-        remote_syncthing.send_eof().unwrap();
-        remote_syncthing.wait_eof().unwrap();
-        remote_syncthing.close().unwrap();
-        remote_syncthing.wait_close().unwrap();
+    }
 
-        println!("Uploading config to remote");
-        // Create remote keypair
-        // set cn to syncthing instead of hostname
-        let remote_keypair = KeyPair::new("syncthing");
-        let remote_key_path = remote_config_folder.join("key.pem");
-        let remote_key_path = Path::new(remote_key_path.as_path().to_str().unwrap());
-        let remote_cert_path = remote_config_folder.join("cert.pem");
-        let remote_cert_path = Path::new(remote_cert_path.as_path().to_str().unwrap());
-        {
-            let mut remote_key =
-                session.scp_send(remote_key_path, 0o640, remote_keypair.key.as_bytes().len() as u64, None)?;
-            remote_key.write_all(remote_keypair.key.as_bytes())?;
-            remote_key.send_eof().unwrap();
-            remote_key.wait_eof().unwrap();
-            remote_key.close().unwrap();
-            remote_key.wait_close().unwrap();
-            let mut remote_cert = session.scp_send(
-                remote_cert_path,
-                0o640,
-                remote_keypair.cert.as_bytes().len() as u64,
-                None,
-            )?;
-            remote_cert.write_all(remote_keypair.cert.as_bytes())?;
-            remote_cert.send_eof().unwrap();
-            remote_cert.wait_eof().unwrap();
-            remote_cert.close().unwrap();
-            remote_cert.wait_close().unwrap();
+    /// Local folder searched for syncthing binaries of platforms that aren't bundled.
+    pub fn cache_folder(&self) -> Result<std::path::PathBuf, ConfError> {
+        match ProjectDirs::from("com", "etromb", "stw") {
+            Some(proj_dirs) => Ok(proj_dirs.cache_dir().to_path_buf()),
+            None => Err(ConfError::NotFound),
         }
-        let remote_device_id = get_device_id(&remote_keypair.cert)?;
+    }
 
-        // generate web ui password
-        let password = generate_password()?;
+    /// Remote config folder for this session and the remote data folder syncthing is uploaded to.
+    fn remote_folders(&self, session: &Session) -> Result<(PathBuf<UnixEncoding>, PathBuf<UnixEncoding>), ConfError> {
+        let (home, _) = exec(session, "eval echo ~$USER")?;
+        let remote_config_folder = PathBuf::<UnixEncoding>::from(home.trim())
+            .join(".config/stw/")
+            .join(self.get_folder());
+        let remote_data_folder = PathBuf::<UnixEncoding>::from(home.trim()).join(".local/share/stw/");
+        Ok((remote_config_folder, remote_data_folder))
+    }
 
-        // Generate local config file
-        let local_folders = self
-            .folders
-            .iter()
-            .map(|x| config::Folder {
-                id: x.get_id(),
-                path: x.local_path.clone(),
-            })
-            .collect();
-        let local_config = ConfigTemplate {
-            local_device_id: local_device_id.clone(),
-            local_device_name: local_hostname.clone(),
-            remote_device_id: remote_device_id.clone(),
-            remote_device_name: remote_hostname.into(),
-            gui_password: password.1.clone(),
-            folders: local_folders,
-        };
+    /// Generate the local and remote configs and upload syncthing, without starting anything. Device keys
+    /// from earlier runs are kept unless `rotate_keys` is set.
+    pub fn init(&mut self, rotate_keys: bool) -> Result<(), ConfError> {
+        let session = self.connect()?;
+        let local = self.prepare_local(rotate_keys)?;
+        let remote = self.provision_remote(&session, rotate_keys)?;
+        let gui = self.write_configs(&session, &local, &remote)?;
+        println!(
+            "Local web ui username = {}\nLocal web ui password = {}",
+            gui.user, gui.password
+        );
+        println!("Session initialized in {:#?}", local.config_folder);
+        Ok(())
+    }
 
-        let local_config_file_path = local_config_folder.join("config.xml");
-        {
-            let mut local_config_file = File::create(&local_config_file_path)?;
-            local_config_file.write_all(local_config.render()?.as_bytes())?;
-        }
+    /// Initialize the session, start the remote syncthing and forward traffic between both sides. Device
+    /// keys from earlier runs are kept unless `rotate_keys` is set. Once both syncthing instances run,
+    /// `started` gets their handles, so they can be stopped on Ctrl-C.
+    pub fn up(
+        &mut self,
+        rotate_keys: bool,
+        started: impl FnOnce(Arc<Mutex<Channel>>, Arc<Supervisor>),
+    ) -> Result<(), ConfError> {
+        let session = self.connect()?;
+        // fail before touching anything if another session or program has one of the ports
+        self.check_ports(&session)?;
+        let tunnels = self.open_tunnels(&session)?;
+        let local = self.prepare_local(rotate_keys)?;
+        let remote = self.provision_remote(&session, rotate_keys)?;
+        let gui = self.write_configs(&session, &local, &remote)?;
+        let local_binary = self.find_local_syncthing(&remote)?;
+        let channel = Arc::new(Mutex::new(self.launch_remote(&session, &remote)?));
+        println!("Remote syncthing started");
+        let local_syncthing = Arc::new(self.launch_local(&local, &local_binary)?);
+        started(channel, local_syncthing.clone());
+        println!(
+            "Local web ui username = {}\nLocal web ui password = {}",
+            gui.user, gui.password
+        );
+        let result = self.run_tunnels(&session, tunnels);
+        local_syncthing.stop();
+        result
+    }
 
-        // Generate remote config file
-        let remote_folders = self
-            .folders
-            .iter()
-            .map(|x| config::Folder {
-                id: x.get_id(),
-                path: x.remote_path.clone(),
-            })
-            .collect();
-        let remote_config = ConfigTemplate {
-            local_device_id: remote_device_id,
-            local_device_name: remote_hostname.into(),
-            remote_device_id: local_device_id,
-            remote_device_name: local_hostname,
-            gui_password: password.1.clone(),
-            folders: remote_folders,
+    /// Report whether the session has been initialized, if the remote syncthing is running and how far
+    /// each folder is synced on both sides.
+    pub fn status(&mut self) -> Result<Status, ConfError> {
+        let local_config_folder = self.local_config_folder()?;
+        let initialized = local_config_folder.join("config.xml").exists();
+        let session = self.connect()?;
+        let (remote_config_folder, _) = self.remote_folders(&session)?;
+        let (_, code) = exec(
+            &session,
+            &format!(
+                "pgrep -f -- {:#?}",
+                format!("serve --home={}", remote_config_folder.as_path().to_string_lossy())
+            ),
+        )?;
+        let remote_running = code == 0;
+
+        let mut status = Status {
+            local_config_folder,
+            remote_config_folder: remote_config_folder.as_path().to_string_lossy().to_string(),
+            initialized,
+            remote_running,
+            connection: None,
+            local_error: None,
+            remote_error: None,
+            folders: self
+                .folders
+                .iter()
+                .map(|x| FolderSync {
+                    id: x.get_id(),
+                    local_path: x.local_path.clone(),
+                    remote_path: x.remote_path.clone(),
+                    local: None,
+                    remote: None,
+                })
+                .collect(),
         };
-        let remote_config = remote_config.render()?;
-
-        let remote_config_file_path = remote_config_folder.join("config.xml");
-        let remote_config_file_path = Path::new(remote_config_file_path.as_path().to_str().unwrap());
-        {
-            let mut remote_config_file = session.scp_send(
-                &remote_config_file_path,
-                0o640,
-                remote_config.as_bytes().len() as u64,
-                None,
-            )?;
-            remote_config_file.write_all(&remote_config.as_bytes())?;
-            remote_config_file.send_eof().unwrap();
-            remote_config_file.wait_eof().unwrap();
-            remote_config_file.close().unwrap();
-            remote_config_file.wait_close().unwrap();
+        if !initialized {
+            return Ok(status);
         }
 
-        // set ssh session to non-blocking
-        session.set_blocking(false);
-
-        // create remote port forward
-        let (mut remote_listener, _) = loop {
-            let remote_listener = session.channel_forward_listen(22001, None, None);
-            if let Ok(remote_listener) = remote_listener {
-                break remote_listener;
+        // the forward makes the session non-blocking, so this goes after the remote commands
+        match self.local_api() {
+            Ok(api) => status.local_error = self.query_side(&api, &mut status, true).err(),
+            Err(e) => status.local_error = Some(e.to_string()),
+        }
+        if remote_running {
+            match self.remote_api(&session) {
+                Ok(api) => status.remote_error = self.query_side(&api, &mut status, false).err(),
+                Err(e) => status.remote_error = Some(e.to_string()),
             }
+        }
+        Ok(status)
+    }
+
+    /// Fill in one side's folder states and, from the first side that answers, the connection state.
+    fn query_side(&self, api: &Client, status: &mut Status, local: bool) -> Result<(), String> {
+        let config = match local {
+            true => &self.local_config,
+            false => &self.remote_config,
         };
+        let peer = config.as_ref().map(|x| x.remote_device_id.clone()).unwrap_or_default();
+        for folder in &mut status.folders {
+            let sync = FolderSideSync::query(api, &folder.id).map_err(|e| e.to_string())?;
+            match local {
+                true => folder.local = Some(sync),
+                false => folder.remote = Some(sync),
+            }
+        }
+        if status.connection.is_none() {
+            let connections = api.connections().map_err(|e| e.to_string())?;
+            status.connection = Some(match connections.connections.get(&peer) {
+                Some(x) if x.paused => "paused".into(),
+                Some(x) if x.connected => format!("connected ({} {})", x.kind, x.address),
+                _ => "disconnected".into(),
+            });
+        }
+        Ok(())
+    }
 
-        // create local listener to forward
-        let local_listener = TcpListener::bind("127.0.0.1:22001")?;
-        local_listener.set_nonblocking(true)?;
+    /// Block until every folder is fully synced on both sides and neither side is scanning or syncing.
+    /// Both folders are rescanned first so recent changes are picked up.
+    pub fn wait(&mut self, timeout: Duration) -> Result<(), ConfError> {
+        let session = self.connect()?;
+        self.wait_on(&session, timeout)
+    }
 
-        // create remote channel to run syncthing
-        let mut channel = loop {
-            let channel = session.channel_session();
-            if let Ok(channel) = channel {
-                break channel;
-            }
+    /// `wait` over an existing session.
+    fn wait_on(&mut self, session: &Session, timeout: Duration) -> Result<(), ConfError> {
+        let deadline = Instant::now() + timeout;
+        let local = self.local_api()?;
+        let remote = self.remote_api(session)?;
+        let local_peer = self
+            .remote_config
+            .as_ref()
+            .ok_or(ConfError::NotInitialized)?
+            .local_device_id
+            .clone();
+        let remote_peer = self
+            .local_config
+            .as_ref()
+            .ok_or(ConfError::NotInitialized)?
+            .local_device_id
+            .clone();
+        let folders: Vec<String> = self.folders.iter().map(Folder::get_id).collect();
+
+        // the scan requests return once the scan is done, so they count against the timeout too
+        let timed_out = |e: crate::st::error::Error| match Instant::now() >= deadline {
+            true => ConfError::Timeout(timeout),
+            false => e.into(),
         };
-        while channel.request_pty("xterm", None, None).is_err() {}
-        while channel
-            .exec(&format!(
-                "{:#?} serve --home={:#?}",
-                remote_syncthing_path,
-                remote_config_folder.as_path().to_str().unwrap()
-            ))
-            .is_err()
-        {}
-        unsafe {
-            CHANNEL.set(Mutex::new(channel)).map_err(|_| ConfError::Channel)?;
+        for folder in &folders {
+            for api in [&local, &*remote] {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(ConfError::Timeout(timeout));
+                }
+                api.scan(folder, None, remaining).map_err(timed_out)?;
+            }
         }
-        println!("Remote syncthing started");
-        println!("Local web ui username = stw\nLocal web ui password = {}", password.0);
-        println!("Run `syncthing serve --home={local_config_folder:#?}` on local machine to sync");
 
+        // event ids are numbered per filter, so start from the subscription the loop polls
+        let mut since = local
+            .events(0, &SYNC_EVENTS, Duration::ZERO)?
+            .last()
+            .map(|x| x.id)
+            .unwrap_or(0);
         loop {
-            // Accepts connection on the remote port
-            match remote_listener.accept() {
-                Ok(channel) => {
-                    // open a stream to the local syncthing server
-                    if let Ok(mut stream) = TcpStream::connect("127.0.0.1:22000") {
-                        stream.set_nonblocking(true)?;
-                        stream.set_nodelay(true)?;
-                        let mut channel_reader = channel.stream(0);
-                        let mut channel_writer = channel.stream(0);
-                        let mut stream_reader = BufReader::new(stream.try_clone()?);
-
-                        thread::spawn(move || loop {
-                            let mut buf = [0_u8; 13312];
-                            match channel_reader.read(&mut buf) {
-                                Ok(amount) => {
-                                    if amount == 0 {
-                                        break;
-                                    }
-                                    stream.write_all(&buf[0..amount]).unwrap();
-                                },
-                                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
-                                Err(x) => {},
-                            }
-                            thread::sleep(Duration::new(0, 10));
-                        });
-
-                        thread::spawn(move || loop {
-                            let mut buf = [0_u8; 13312];
-                            match stream_reader.read(&mut buf) {
-                                Ok(amount) => {
-                                    if amount == 0 {
-                                        break;
-                                    }
-                                    channel_writer.write_all(&buf[0..amount]).unwrap();
-                                },
-                                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
-                                Err(x) => {},
-                            }
-                            thread::sleep(Duration::new(0, 10));
-                        });
-                    }
-                },
-                Err(x) => {},
-            };
-            match local_listener.accept() {
-                Ok((mut stream, _)) => {
-                    stream.set_nonblocking(true)?;
-                    stream.set_nodelay(true)?;
-
-                    if let Some(channel) = loop {
-                        match session.channel_direct_tcpip("127.0.0.1", 22000, None) {
-                            Ok(x) => break Some(x),
-                            Err(x) if x.code() == ssh2::ErrorCode::Session(-37) => continue,
-                            Err(x) => {
-                                println!("{:#?}", x);
-                                break None;
-                            },
-                        }
-                    } {
-                        let mut channel_reader = channel.stream(0);
-                        let mut channel_writer = channel.stream(0);
-                        let mut stream_reader = BufReader::new(stream.try_clone()?);
-
-                        thread::spawn(move || loop {
-                            let mut buf = [0_u8; 13312];
-                            match channel_reader.read(&mut buf) {
-                                Ok(amount) => {
-                                    if amount == 0 {
-                                        break;
-                                    }
-                                    stream.write_all(&buf[0..amount]).unwrap();
-                                },
-                                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
-                                Err(x) => {},
-                            }
-                            thread::sleep(Duration::new(0, 1));
-                        });
-
-                        thread::spawn(move || loop {
-                            let mut buf = [0_u8; 13312];
-                            match stream_reader.read(&mut buf) {
-                                Ok(amount) => {
-                                    if amount == 0 {
-                                        break;
-                                    }
-                                    channel_writer.write_all(&buf[0..amount]).unwrap();
-                                },
-                                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
-                                Err(x) => {},
-                            }
-                            thread::sleep(Duration::new(0, 10));
-                        });
-                    }
-                },
-                Err(_) => {},
+            let mut synced = true;
+            for folder in &folders {
+                synced = synced && in_sync(&local, folder, &local_peer)? && in_sync(&remote, folder, &remote_peer)?;
+            }
+            if synced {
+                return Ok(());
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(ConfError::Timeout(timeout));
             }
-            thread::sleep(Duration::new(0, 1));
+            // remote progress shows up in the local events too, the timeout catches the rest. syncthing
+            // counts it in whole seconds and answers right away for 0, so the last wait may overshoot a bit.
+            let poll = Duration::from_secs(remaining.min(WAIT_POLL).as_secs().max(1));
+            let events = local.events(since, &SYNC_EVENTS, poll)?;
+            if let Some(event) = events.last() {
+                since = event.id;
+            }
+        }
+    }
+
+    /// Wait for sync, then run `command` on the remote in the folder the current directory maps to and
+    /// return its exit status. With `sync_back` it also waits for the command's output to sync back.
+    pub fn exec(&mut self, command: &[String], sync_back: bool, timeout: Duration) -> Result<i32, ConfError> {
+        let cwd = env::current_dir()?;
+        let remote_dir = self.remote_path_for(&cwd).ok_or(ConfError::NotInFolder(cwd))?;
+        let session = self.connect()?;
+        self.wait_on(&session, timeout)?;
+
+        // joined like ssh does, so the command can use shell syntax
+        let command = format!(
+            "cd {} && {}",
+            shell_quote(&remote_dir.as_path().to_string_lossy()),
+            command.join(" ")
+        );
+        let code = run_pty(&session, &command)?;
+        if sync_back {
+            self.wait_on(&session, timeout)?;
+        }
+        Ok(code)
+    }
+
+    /// Remote path that a local path syncs to, if it's inside one of the folders. Nested folders map
+    /// with the innermost one.
+    pub fn remote_path_for(&self, local: &Path) -> Option<PathBuf<UnixEncoding>> {
+        let local = local.canonicalize().unwrap_or_else(|_| local.to_path_buf());
+        self.folders
+            .iter()
+            .filter_map(|folder| {
+                let root = std::path::PathBuf::from(expand_home(&folder.local_path));
+                let root = root.canonicalize().unwrap_or(root);
+                let rest = local.strip_prefix(&root).ok()?;
+                Some((root.components().count(), folder, rest.to_path_buf()))
+            })
+            .max_by_key(|(depth, _, _)| *depth)
+            .map(|(_, folder, rest)| {
+                let mut remote = PathBuf::<UnixEncoding>::from(folder.remote_path.as_str());
+                for part in rest.components() {
+                    remote.push(part.as_os_str().to_string_lossy().as_ref());
+                }
+                remote
+            })
+    }
+
+    /// Local path that a remote path syncs to, if it's inside one of the folders. The reverse of
+    /// `remote_path_for`.
+    pub fn local_path_for(&self, remote: &PathBuf<UnixEncoding>) -> Option<std::path::PathBuf> {
+        self.folders
+            .iter()
+            .filter_map(|folder| {
+                let root = PathBuf::<UnixEncoding>::from(folder.remote_path.as_str());
+                let rest = remote.strip_prefix(&root).ok()?;
+                Some((root.components().count(), folder, rest.to_path_buf()))
+            })
+            .max_by_key(|(depth, _, _)| *depth)
+            .map(|(_, folder, rest)| {
+                let mut local = std::path::PathBuf::from(expand_home(&folder.local_path));
+                for part in rest.components() {
+                    local.push(String::from_utf8_lossy(part.as_bytes()).as_ref());
+                }
+                local
+            })
+    }
+
+    /// Open an interactive shell on the remote, in the folder the current directory maps to if there is
+    /// one. Returns the shell's exit status.
+    pub fn ssh(&self) -> Result<i32, ConfError> {
+        let cwd = env::current_dir()?;
+        let remote_dir = self.remote_path_for(&cwd);
+        if remote_dir.is_none() {
+            warn!(
+                "{:#?} isn't in any of the synced folders, starting in the remote home",
+                cwd
+            );
+        }
+        let session = self.connect()?;
+        let dir = remote_dir.map(|x| x.as_path().to_string_lossy().to_string());
+        Ok(interactive_shell(&session, dir.as_deref())?)
+    }
+
+    /// Stop the remote syncthing instance. Returns false if it wasn't running.
+    pub fn down(&self) -> Result<bool, ConfError> {
+        let session = self.connect()?;
+        self.stop_remote(&session)
+    }
+
+    /// Stop the remote syncthing and remove the local and remote session configs.
+    pub fn clean(&self) -> Result<(), ConfError> {
+        let session = self.connect()?;
+        self.stop_remote(&session)?;
+        let (remote_config_folder, _) = self.remote_folders(&session)?;
+        let (_, code) = exec(
+            &session,
+            &format!("rm -rf {:#?}", remote_config_folder.as_path().to_string_lossy()),
+        )?;
+        if code != 0 {
+            return Err(ConfError::RemoteFolder);
+        }
+        let local_config_folder = self.local_config_folder()?;
+        if local_config_folder.exists() {
+            fs::remove_dir_all(&local_config_folder)?;
         }
         Ok(())
     }
+
+    fn stop_remote(&self, session: &Session) -> Result<bool, ConfError> {
+        let (remote_config_folder, _) = self.remote_folders(session)?;
+        let (_, code) = exec(
+            session,
+            &format!(
+                "pkill -f -- {:#?}",
+                format!("serve --home={}", remote_config_folder.as_path().to_string_lossy())
+            ),
+        )?;
+        Ok(code == 0)
+    }
+}
+
+/// Events that can mean a folder's sync state changed.
+const SYNC_EVENTS: [&str; 6] = [
+    "StateChanged",
+    "FolderCompletion",
+    "FolderSummary",
+    "LocalIndexUpdated",
+    "RemoteIndexUpdated",
+    "DeviceConnected",
+];
+/// Longest wait for events before `Conf::wait` checks again.
+const WAIT_POLL: Duration = Duration::from_secs(5);
+
+/// True if the folder is idle and both this side and `peer` have everything.
+fn in_sync(api: &Client, folder: &str, peer: &str) -> Result<bool, ConfError> {
+    let status = api.folder_status(folder)?;
+    if status.state != "idle" || status.need_bytes > 0 || status.need_files > 0 {
+        return Ok(false);
+    }
+    Ok(api.completion(Some(folder), None)?.completion >= 100.0
+        && api.completion(Some(folder), Some(peer))?.completion >= 100.0)
+}
+
+/// State of a session as reported by `Conf::status`.
+#[derive(Clone, Debug, Serialize)]
+pub struct Status {
+    pub local_config_folder: std::path::PathBuf,
+    pub remote_config_folder: String,
+    pub initialized: bool,
+    pub remote_running: bool,
+    /// Connection between the two syncthing instances, if either could be asked
+    pub connection: Option<String>,
+    /// Why the local syncthing couldn't be asked about the folders
+    pub local_error: Option<String>,
+    /// Why the remote syncthing couldn't be asked about the folders
+    pub remote_error: Option<String>,
+    pub folders: Vec<FolderSync>,
+}
+
+/// Sync state of a `Folder` on both sides.
+#[derive(Clone, Debug, Serialize)]
+pub struct FolderSync {
+    pub id: String,
+    pub local_path: String,
+    pub remote_path: String,
+    pub local: Option<FolderSideSync>,
+    pub remote: Option<FolderSideSync>,
+}
+
+/// Sync state of a folder as one syncthing instance sees it.
+#[derive(Clone, Debug, Serialize)]
+pub struct FolderSideSync {
+    /// `idle`, `scanning`, `syncing`, `error`, ...
+    pub state: String,
+    /// Percentage of the global state this side has
+    pub completion: f64,
+    pub need_bytes: u64,
+    pub need_files: u64,
+    pub local_files: u64,
+    pub global_files: u64,
+    /// Folder and pull errors
+    pub errors: u64,
+    pub error: String,
+}
+
+impl FolderSideSync {
+    fn query(api: &Client, folder: &str) -> Result<FolderSideSync, crate::st::error::Error> {
+        let status = api.folder_status(folder)?;
+        let completion = api.completion(Some(folder), None)?;
+        Ok(FolderSideSync {
+            state: status.state,
+            completion: completion.completion,
+            need_bytes: status.need_bytes,
+            need_files: status.need_files,
+            local_files: status.local_files,
+            global_files: status.global_files,
+            errors: status.errors + status.pull_errors,
+            error: status.error,
+        })
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -424,61 +597,118 @@ impl Folder {
     }
 }
 
+/// Device key algorithm. syncthing generates P-384 keys itself.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyAlgorithm {
+    Ed25519,
+    #[serde(rename = "p-256")]
+    P256,
+    #[default]
+    #[serde(rename = "p-384")]
+    P384,
+    #[serde(rename = "rsa-3072")]
+    Rsa3072,
+}
+
+/// Validity of generated device certificates, the same as syncthing's.
+pub const DEFAULT_KEY_VALIDITY_DAYS: u32 = 3650;
+
+/// syncthing device key and certificate, PEM encoded.
 pub struct KeyPair {
     key: String,
     cert: String,
 }
 
 impl KeyPair {
-    pub fn new(cn: &str) -> KeyPair {
-        let ec_group = EcGroup::from_curve_name(Nid::SECP384R1).unwrap();
-        let ec = EcKey::generate(&ec_group).unwrap();
-        let pkey = PKey::from_ec_key(ec).unwrap();
-
-        let mut subject_name = openssl::x509::X509NameBuilder::new().unwrap();
-        subject_name.append_entry_by_text("O", "Syncthing").unwrap();
-        subject_name
-            .append_entry_by_text("OU", "Automatically Generated")
-            .unwrap();
-        subject_name.append_entry_by_text("CN", cn).unwrap();
+    /// Generate a key and a self-signed certificate for `cn`, valid for `validity_days`.
+    pub fn new(cn: &str, algorithm: KeyAlgorithm, validity_days: u32) -> Result<KeyPair, ConfError> {
+        let pkey = match algorithm {
+            KeyAlgorithm::Ed25519 => PKey::generate_ed25519()?,
+            KeyAlgorithm::P256 => ec_key(Nid::X9_62_PRIME256V1)?,
+            KeyAlgorithm::P384 => ec_key(Nid::SECP384R1)?,
+            KeyAlgorithm::Rsa3072 => PKey::from_rsa(Rsa::generate(3072)?)?,
+        };
+
+        let mut subject_name = openssl::x509::X509NameBuilder::new()?;
+        subject_name.append_entry_by_text("O", "Syncthing")?;
+        subject_name.append_entry_by_text("OU", "Automatically Generated")?;
+        subject_name.append_entry_by_text("CN", cn)?;
         let subject_name = subject_name.build();
-        let mut cert = X509::builder().unwrap();
-        cert.set_version(2).unwrap();
+        let mut cert = X509::builder()?;
+        cert.set_version(2)?;
         let serial_number = {
-            let mut serial = BigNum::new().unwrap();
-            serial.rand(159, MsbOption::MAYBE_ZERO, false).unwrap();
-            serial.to_asn1_integer().unwrap()
+            let mut serial = BigNum::new()?;
+            serial.rand(159, MsbOption::MAYBE_ZERO, false)?;
+            serial.to_asn1_integer()?
         };
-        cert.set_serial_number(&serial_number).unwrap();
+        cert.set_serial_number(&serial_number)?;
         let context = cert.x509v3_context(None, None);
-        let alternate_name =
-            X509Extension::new(None, Some(&context), "subjectAltName", &format!("DNS:{}", cn)).unwrap();
-        cert.append_extension(alternate_name).unwrap();
+        let alternate_name = X509Extension::new(None, Some(&context), "subjectAltName", &format!("DNS:{}", cn))?;
+        cert.append_extension(alternate_name)?;
+        // only RSA keys can encipher
+        let key_usage = match algorithm {
+            KeyAlgorithm::Rsa3072 => "keyEncipherment, digitalSignature",
+            _ => "digitalSignature",
+        };
         let context = cert.x509v3_context(None, None);
-        let key_usage =
-            X509Extension::new(None, Some(&context), "keyUsage", "keyEncipherment, digitalSignature").unwrap();
-        cert.append_extension(key_usage).unwrap();
+        let key_usage = X509Extension::new(None, Some(&context), "keyUsage", key_usage)?;
+        cert.append_extension(key_usage)?;
         let context = cert.x509v3_context(None, None);
         let extended_key_usage =
-            X509Extension::new(None, Some(&context), "extendedKeyUsage", "serverAuth, clientAuth").unwrap();
-        cert.append_extension(extended_key_usage).unwrap();
-        cert.set_subject_name(&subject_name).unwrap();
-        cert.set_pubkey(&pkey).unwrap();
-        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
-        cert.set_not_after(&Asn1Time::days_from_now(3650).unwrap()).unwrap();
-        cert.sign(&pkey, MessageDigest::sha256()).unwrap();
-        let cert = String::from_utf8(cert.build().to_pem().unwrap()).unwrap();
-        let key = String::from_utf8(pkey.private_key_to_pem_pkcs8().unwrap()).unwrap();
-        KeyPair { key, cert }
+            X509Extension::new(None, Some(&context), "extendedKeyUsage", "serverAuth, clientAuth")?;
+        cert.append_extension(extended_key_usage)?;
+        cert.set_subject_name(&subject_name)?;
+        cert.set_pubkey(&pkey)?;
+        cert.set_not_before(&*Asn1Time::days_from_now(0)?)?;
+        cert.set_not_after(&*Asn1Time::days_from_now(validity_days)?)?;
+        // Ed25519 signs the message itself, without a separate digest
+        let digest = match algorithm {
+            KeyAlgorithm::Ed25519 => MessageDigest::null(),
+            _ => MessageDigest::sha256(),
+        };
+        cert.sign(&pkey, digest)?;
+        let cert = String::from_utf8_lossy(&cert.build().to_pem()?).into();
+        let key = String::from_utf8_lossy(&pkey.private_key_to_pem_pkcs8()?).into();
+        Ok(KeyPair { key, cert })
+    }
+
+    /// Load a keypair from the contents of `key.pem` and `cert.pem`, checking that the key belongs to the
+    /// certificate.
+    pub fn from_pem(key: &[u8], cert: &[u8]) -> Result<KeyPair, ConfError> {
+        let pkey = PKey::private_key_from_pem(key)?;
+        let x509 = X509::from_pem(cert)?;
+        if !x509.public_key()?.public_eq(&pkey) {
+            return Err(ConfError::KeyMismatch);
+        }
+        Ok(KeyPair {
+            key: String::from_utf8_lossy(key).into(),
+            cert: String::from_utf8_lossy(cert).into(),
+        })
+    }
+
+    /// Load a keypair from `key.pem` and `cert.pem` files.
+    pub fn from_files(key: &Path, cert: &Path) -> Result<KeyPair, ConfError> {
+        KeyPair::from_pem(&fs::read(key)?, &fs::read(cert)?)
     }
 }
 
+fn ec_key(curve: Nid) -> Result<PKey<Private>, ConfError> {
+    let group = EcGroup::from_curve_name(curve)?;
+    Ok(PKey::from_ec_key(EcKey::generate(&group)?)?)
+}
+
+/// Load the config from `conf_path`, which may be the config file itself or a directory containing `config.yml`.
+/// Defaults to `config.yml` in the current directory.
 pub fn load_config(conf_path: Option<String>) -> Result<Conf, ConfError> {
-    let conf_dir = match conf_path {
+    let conf_path: std::path::PathBuf = match conf_path {
         Some(path) => path.into(),
         None => env::current_dir()?,
     };
-    let conf_file = conf_dir.join("config.yml");
+    let conf_file = match conf_path.is_dir() {
+        true => conf_path.join("config.yml"),
+        false => conf_path,
+    };
     let conf: Conf = serde_yaml::from_str(&fs::read_to_string(conf_file)?)?;
     Ok(conf)
 }
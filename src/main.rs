@@ -1,11 +1,109 @@
 #[cfg(any(target_os = "linux", target_os = "macos"))]
 use signal_hook::{consts::SIGINT, iterator::Signals, low_level};
 
-use std::{io::Write, process::exit, thread};
+use anyhow::{bail, Result};
+use clap::{Parser, Subcommand};
+use log::LevelFilter;
+use once_cell::sync::OnceCell;
+use ssh2::Channel;
+use std::{
+    env,
+    io::Write,
+    path::PathBuf,
+    process::exit,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use typed_path::{PathBuf as TypedPathBuf, UnixEncoding};
 
-use stw::{config::load_config, CHANNEL};
+use stw::{
+    config::{load_config, Status},
+    st::process::Supervisor,
+};
+
+/// Remote syncthing started by `stw up`, interrupted on Ctrl-C.
+static CHANNEL: OnceCell<Arc<Mutex<Channel>>> = OnceCell::new();
+/// Local syncthing started by `stw up`, stopped on Ctrl-C.
+static LOCAL_SYNCTHING: OnceCell<Arc<Supervisor>> = OnceCell::new();
+
+/// SyncThing wrapper for remote development
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Cli {
+    /// Path to config.yml, or the directory containing it
+    #[arg(short, long, global = true)]
+    config: Option<String>,
+    /// Print debug output
+    #[arg(short, long, global = true)]
+    verbose: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Initialize the session, start remote syncthing and run the ssh tunnels
+    Up {
+        /// Replace the device keys, other devices paired with this session have to be paired again
+        #[arg(long)]
+        rotate_keys: bool,
+    },
+    /// Generate configs and upload syncthing to the remote without starting it
+    Init {
+        /// Replace the device keys, other devices paired with this session have to be paired again
+        #[arg(long)]
+        rotate_keys: bool,
+    },
+    /// Show whether remote syncthing is running and how far each folder is synced
+    Status {
+        /// Print the status as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Wait until every folder is fully synced on both sides
+    Wait {
+        /// Give up after this many seconds
+        #[arg(long, default_value_t = 600)]
+        timeout: u64,
+    },
+    /// Wait for sync, then run a command on the remote in the folder matching the current directory
+    Exec {
+        /// Also wait for the command's output to sync back
+        #[arg(long)]
+        sync_back: bool,
+        /// Give up waiting for sync after this many seconds
+        #[arg(long, default_value_t = 600)]
+        timeout: u64,
+        /// Command to run, after `--`
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+    /// Print the remote path a local path syncs to
+    Path {
+        /// Defaults to the current directory
+        path: Option<String>,
+        /// Map a remote path to the local one instead
+        #[arg(short, long)]
+        reverse: bool,
+    },
+    /// Open a shell on the remote in the folder matching the current directory
+    Ssh,
+    /// Stop the remote syncthing instance
+    Down,
+    /// Stop remote syncthing and remove the local and remote session configs
+    Clean,
+}
 
 fn main() {
+    let cli = Cli::parse();
+    env_logger::Builder::new()
+        .filter_level(match cli.verbose {
+            true => LevelFilter::Debug,
+            false => LevelFilter::Info,
+        })
+        .init();
+
     #[cfg(target_os = "windows")]
     wintrap::trap(
         &[
@@ -14,30 +112,155 @@ fn main() {
             wintrap::Signal::CloseWindow,
         ],
         |_| {
-            unsafe {
-                if let Some(channel) = CHANNEL.get() {
-                    let mut channel = channel.lock().unwrap();
-                    let mut stream = channel.stream(0);
-                    let ctrl_c = format!("{}", 3 as char);
-                    while stream.write(ctrl_c.as_bytes()).is_err() {}
-                    while channel.send_eof().is_err() {}
-                    while channel.close().is_err() {}
-                }
+            if let Some(channel) = CHANNEL.get() {
+                let mut channel = channel.lock().unwrap();
+                let mut stream = channel.stream(0);
+                let ctrl_c = format!("{}", 3 as char);
+                while stream.write(ctrl_c.as_bytes()).is_err() {}
+                while channel.send_eof().is_err() {}
+                while channel.close().is_err() {}
+            }
+            if let Some(local) = LOCAL_SYNCTHING.get() {
+                local.stop();
             }
             exit(0);
         },
-        move || {
-            let mut config = load_config(None).unwrap();
-            config.generate_config_templates().unwrap();
-        },
+        move || finish(run(cli)),
     )
     .unwrap();
 
     #[cfg(any(target_os = "linux", target_os = "macos"))]
     {
         init();
-        let mut config = load_config(None).unwrap();
-        config.generate_config_templates().unwrap();
+        finish(run(cli));
+    }
+}
+
+fn run(cli: Cli) -> Result<()> {
+    let mut config = load_config(cli.config)?;
+    match cli.command {
+        Command::Up { rotate_keys } => config.up(rotate_keys, |channel, local| {
+            let _ = CHANNEL.set(channel);
+            let _ = LOCAL_SYNCTHING.set(local);
+        })?,
+        Command::Init { rotate_keys } => config.init(rotate_keys)?,
+        Command::Status { json } => {
+            let status = config.status()?;
+            match json {
+                true => println!("{}", serde_json::to_string_pretty(&status)?),
+                false => print_status(&status),
+            }
+        },
+        Command::Wait { timeout } => {
+            config.wait(Duration::from_secs(timeout))?;
+            println!("All folders are in sync");
+        },
+        Command::Exec {
+            sync_back,
+            timeout,
+            command,
+        } => {
+            let code = config.exec(&command, sync_back, Duration::from_secs(timeout))?;
+            if code != 0 {
+                exit(code);
+            }
+        },
+        Command::Path { path, reverse } => {
+            let mapped = match (reverse, path) {
+                (true, Some(path)) => config
+                    .local_path_for(&TypedPathBuf::<UnixEncoding>::from(path.as_str()))
+                    .map(|x| x.to_string_lossy().to_string()),
+                (true, None) => bail!("--reverse needs a remote path"),
+                (false, path) => {
+                    let path = match path {
+                        Some(path) => PathBuf::from(path),
+                        None => env::current_dir()?,
+                    };
+                    config
+                        .remote_path_for(&path)
+                        .map(|x| x.as_path().to_string_lossy().to_string())
+                },
+            };
+            match mapped {
+                Some(mapped) => println!("{}", mapped),
+                None => bail!("Path isn't in any of the synced folders"),
+            }
+        },
+        Command::Ssh => {
+            let code = config.ssh()?;
+            if code != 0 {
+                exit(code);
+            }
+        },
+        Command::Down => match config.down()? {
+            true => println!("Remote syncthing stopped"),
+            false => println!("Remote syncthing wasn't running"),
+        },
+        Command::Clean => {
+            config.clean()?;
+            println!("Removed local and remote session configs");
+        },
+    }
+    Ok(())
+}
+
+fn print_status(status: &Status) {
+    println!("Local config folder: {:#?}", status.local_config_folder);
+    println!("Remote config folder: {:#?}", status.remote_config_folder);
+    println!("Initialized: {}", status.initialized);
+    println!("Remote syncthing running: {}", status.remote_running);
+    if let Some(connection) = &status.connection {
+        println!("Connection: {}", connection);
+    }
+    if let Some(error) = &status.local_error {
+        println!("Local syncthing: {}", error);
+    }
+    if let Some(error) = &status.remote_error {
+        println!("Remote syncthing: {}", error);
+    }
+    for folder in &status.folders {
+        println!("{} -> {}", folder.local_path, folder.remote_path);
+        for (side, sync) in [("local", &folder.local), ("remote", &folder.remote)] {
+            match sync {
+                Some(sync) => {
+                    println!(
+                        "  {:<7} {}, {:.2}% ({} needed), {}/{} files, {} errors",
+                        format!("{}:", side),
+                        sync.state,
+                        sync.completion,
+                        format_bytes(sync.need_bytes),
+                        sync.local_files,
+                        sync.global_files,
+                        sync.errors
+                    );
+                    if !sync.error.is_empty() {
+                        println!("          {}", sync.error);
+                    }
+                },
+                None => println!("  {:<7} unknown", format!("{}:", side)),
+            }
+        }
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", value, units[unit]),
+    }
+}
+
+fn finish(result: Result<()>) {
+    if let Err(e) = result {
+        eprintln!("Error: {:#}", e);
+        exit(1);
     }
 }
 
@@ -47,19 +270,20 @@ fn init() {
 
     thread::spawn(move || {
         for _sig in signals.forever() {
-            unsafe {
-                if let Some(channel) = CHANNEL.get() {
-                    let mut channel = channel.lock().unwrap();
-                    if !channel.eof() {
-                        let mut stream = channel.stream(0);
-                        let ctrl_c = format!("{}", 3 as char);
-                        while stream.write(ctrl_c.as_bytes()).is_err() {}
-                        while channel.send_eof().is_err() {}
-                    }
-                    while channel.close().is_err() {}
+            if let Some(channel) = CHANNEL.get() {
+                let mut channel = channel.lock().unwrap();
+                if !channel.eof() {
+                    let mut stream = channel.stream(0);
+                    let ctrl_c = format!("{}", 3 as char);
+                    while stream.write(ctrl_c.as_bytes()).is_err() {}
+                    while channel.send_eof().is_err() {}
                 }
-                low_level::emulate_default_handler(SIGINT).unwrap();
+                while channel.close().is_err() {}
+            }
+            if let Some(local) = LOCAL_SYNCTHING.get() {
+                local.stop();
             }
+            low_level::emulate_default_handler(SIGINT).unwrap();
         }
     });
 }
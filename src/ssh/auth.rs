@@ -0,0 +1,149 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::debug;
+use rpassword::prompt_password;
+use ssh2::{KeyboardInteractivePrompt, Prompt, Session};
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::ssh::{known_hosts::key_fingerprint, SshError};
+
+/// Methods stw can authenticate with, as named in `auth_methods`.
+const SUPPORTED_METHODS: [&str; 3] = ["publickey", "keyboard-interactive", "password"];
+
+/// Authenticate with the methods the server advertises, in the order it lists them. A server requiring
+/// several methods (e.g. a key and then an OTP) answers with a shorter list after each partial success,
+/// so the list is fetched again after every attempt.
+pub fn authenticate(session: &Session, user: &str, key: Option<&PathBuf>) -> Result<(), SshError> {
+    let mut methods = String::new();
+    let mut tried: Vec<&str> = Vec::new();
+    while !session.authenticated() {
+        let advertised = match session.auth_methods(user) {
+            Ok(advertised) => advertised.to_string(),
+            // the server let us in with the `none` method
+            Err(_) if session.authenticated() => break,
+            Err(e) => return Err(e.into()),
+        };
+        if advertised != methods {
+            debug!("server accepts {}", advertised);
+            methods = advertised;
+            tried.clear();
+        }
+        let method = methods
+            .split(',')
+            .filter_map(|x| SUPPORTED_METHODS.iter().find(|method| **method == x))
+            .find(|x| !tried.contains(x))
+            .ok_or_else(|| SshError::Auth(methods.clone()))?;
+        tried.push(method);
+
+        let result = match *method {
+            "publickey" => userauth_publickey(session, user, key),
+            "keyboard-interactive" => session
+                .userauth_keyboard_interactive(user, &mut TerminalPrompter)
+                .map_err(SshError::from),
+            _ => {
+                let password = prompt_password(format!("{}'s password: ", user))?;
+                session.userauth_password(user, &password).map_err(SshError::from)
+            },
+        };
+        if let Err(e) = result {
+            debug!("{} authentication failed: {}", method, e);
+        }
+    }
+    Ok(())
+}
+
+/// Try the agent, then the key file, prompting for its passphrase if needed.
+fn userauth_publickey(session: &Session, user: &str, key: Option<&PathBuf>) -> Result<(), SshError> {
+    if userauth_agent(session, user, key.map(PathBuf::as_path))? {
+        return Ok(());
+    }
+    if let Some(key) = key {
+        let pubkey = public_key_path(key);
+        let pubkey = match pubkey.exists() {
+            true => Some(pubkey.as_path()),
+            false => None,
+        };
+        if session.userauth_pubkey_file(user, pubkey, key, None).is_err() {
+            let password = prompt_password(format!("Enter passphrase for key {:#?}: ", key))?;
+            session.userauth_pubkey_file(user, pubkey, key, Some(&password))?
+        }
+    }
+    Ok(())
+}
+
+/// Relays keyboard-interactive prompts (OTP, Duo, ...) to the terminal.
+struct TerminalPrompter;
+
+impl KeyboardInteractivePrompt for TerminalPrompter {
+    fn prompt<'a>(&mut self, _username: &str, instructions: &str, prompts: &[Prompt<'a>]) -> Vec<String> {
+        if !instructions.is_empty() {
+            println!("{}", instructions);
+        }
+        prompts
+            .iter()
+            .map(|prompt| {
+                let answer = match prompt.echo {
+                    true => {
+                        print!("{}", prompt.text);
+                        let _ = io::stdout().flush();
+                        let mut answer = String::new();
+                        io::stdin()
+                            .read_line(&mut answer)
+                            .map(|_| answer.trim_end().to_string())
+                    },
+                    false => prompt_password(prompt.text.as_ref()),
+                };
+                answer.unwrap_or_default()
+            })
+            .collect()
+    }
+}
+
+/// Try every identity in ssh-agent, starting with the one matching `key` if there is one. Returns false
+/// if the agent isn't running or none of its identities were accepted.
+fn userauth_agent(session: &Session, user: &str, key: Option<&Path>) -> Result<bool, SshError> {
+    let mut agent = session.agent()?;
+    if agent.connect().is_err() {
+        debug!("ssh-agent isn't running");
+        return Ok(false);
+    }
+    agent.list_identities()?;
+    let mut identities = agent.identities()?;
+    if let Some(blob) = key.and_then(public_key_blob) {
+        // stable sort, so the matching identity goes first and the rest keep the agent's order
+        identities.sort_by_key(|x| x.blob() != blob.as_slice());
+    }
+    for identity in &identities {
+        match agent.userauth(user, identity) {
+            Ok(()) => {
+                println!(
+                    "Authenticated with agent key {} ({})",
+                    key_fingerprint(identity.blob()),
+                    identity.comment()
+                );
+                let _ = agent.disconnect();
+                return Ok(true);
+            },
+            Err(e) => debug!("agent key {} rejected: {}", key_fingerprint(identity.blob()), e),
+        }
+    }
+    let _ = agent.disconnect();
+    Ok(false)
+}
+
+/// `<key>.pub`
+fn public_key_path(key: &Path) -> PathBuf {
+    let mut path = key.as_os_str().to_owned();
+    path.push(".pub");
+    path.into()
+}
+
+/// Decoded key blob from `<key>.pub`, in the same format the agent reports identities in.
+fn public_key_blob(key: &Path) -> Option<Vec<u8>> {
+    let contents = fs::read_to_string(public_key_path(key)).ok()?;
+    let encoded = contents.split_whitespace().nth(1)?;
+    STANDARD.decode(encoded).ok()
+}
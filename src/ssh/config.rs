@@ -0,0 +1,403 @@
+use directories::BaseDirs;
+use log::debug;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::ssh::{known_hosts::StrictHostKeyChecking, SshError};
+
+/// Options from `~/.ssh/config` that apply to one host.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HostConfig {
+    pub host_name: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub identity_files: Vec<String>,
+    pub proxy_jump: Option<String>,
+    pub strict_host_key_checking: Option<StrictHostKeyChecking>,
+}
+
+/// A single option line and the `Host` patterns it's scoped to.
+#[derive(Clone, Debug)]
+struct Entry {
+    patterns: Vec<String>,
+    keyword: String,
+    value: String,
+}
+
+/// Parsed OpenSSH client config.
+#[derive(Clone, Debug, Default)]
+pub struct SshConfig {
+    entries: Vec<Entry>,
+}
+
+impl SshConfig {
+    /// Load `~/.ssh/config`, returning an empty config if it doesn't exist.
+    pub fn load_default() -> Result<SshConfig, SshError> {
+        match ssh_dir() {
+            Some(dir) if dir.join("config").exists() => SshConfig::load(dir.join("config")),
+            _ => Ok(SshConfig::default()),
+        }
+    }
+
+    /// Load a config file, following `Include` directives.
+    pub fn load(path: impl AsRef<Path>) -> Result<SshConfig, SshError> {
+        let mut config = SshConfig::default();
+        let mut patterns = vec!["*".to_string()];
+        config.read_file(path.as_ref(), &mut patterns, 0)?;
+        Ok(config)
+    }
+
+    /// Parse config file contents. `Include` directives are resolved relative to `~/.ssh`.
+    pub fn parse(contents: &str) -> Result<SshConfig, SshError> {
+        let mut config = SshConfig::default();
+        let mut patterns = vec!["*".to_string()];
+        config.parse_into(contents, &mut patterns, 0)?;
+        Ok(config)
+    }
+
+    fn read_file(&mut self, path: &Path, patterns: &mut Vec<String>, depth: usize) -> Result<(), SshError> {
+        debug!("reading ssh config {:#?}", path);
+        let contents = fs::read_to_string(path)?;
+        self.parse_into(&contents, patterns, depth)
+    }
+
+    fn parse_into(&mut self, contents: &str, patterns: &mut Vec<String>, depth: usize) -> Result<(), SshError> {
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (keyword, value) = match line.split_once(|c: char| c.is_whitespace() || c == '=') {
+                Some((keyword, value)) => (
+                    keyword,
+                    value.trim_start_matches(|c: char| c.is_whitespace() || c == '='),
+                ),
+                None => (line, ""),
+            };
+            let keyword = keyword.to_lowercase();
+            let args = split_args(value);
+            match keyword.as_str() {
+                "host" => *patterns = args,
+                // Match blocks aren't supported, skip everything in them
+                "match" => *patterns = Vec::new(),
+                "include" => {
+                    // OpenSSH limits include depth to 16
+                    if depth >= 16 {
+                        continue;
+                    }
+                    // Host lines in the included files only apply there, like in OpenSSH
+                    let outer = patterns.clone();
+                    for arg in args {
+                        for path in expand_include(&arg) {
+                            self.read_file(&path, patterns, depth + 1)?;
+                            *patterns = outer.clone();
+                        }
+                    }
+                },
+                _ => self.entries.push(Entry {
+                    patterns: patterns.clone(),
+                    keyword,
+                    value: args.join(" "),
+                }),
+            }
+        }
+        Ok(())
+    }
+
+    /// Collect the options for `host`. As in OpenSSH the first value found for an option wins,
+    /// except `IdentityFile` which accumulates.
+    pub fn query(&self, host: &str) -> HostConfig {
+        let mut config = HostConfig::default();
+        for entry in self.entries.iter().filter(|x| host_matches(&x.patterns, host)) {
+            match entry.keyword.as_str() {
+                "hostname" if config.host_name.is_none() => config.host_name = Some(entry.value.clone()),
+                "user" if config.user.is_none() => config.user = Some(entry.value.clone()),
+                "port" if config.port.is_none() => config.port = entry.value.parse().ok(),
+                "identityfile" => config.identity_files.push(entry.value.clone()),
+                "proxyjump" if config.proxy_jump.is_none() => config.proxy_jump = Some(entry.value.clone()),
+                "stricthostkeychecking" if config.strict_host_key_checking.is_none() => {
+                    config.strict_host_key_checking = StrictHostKeyChecking::from_ssh_config(&entry.value)
+                },
+                _ => {},
+            }
+        }
+
+        // expand tokens now that hostname, user and port are known
+        let host_name = config
+            .host_name
+            .as_ref()
+            .map(|x| expand_tokens(x, host, host, config.user.as_deref(), config.port));
+        let original_host = host;
+        let host = host_name.clone().unwrap_or_else(|| host.into());
+        config.identity_files = config
+            .identity_files
+            .iter()
+            .map(|x| expand_tokens(x, &host, original_host, config.user.as_deref(), config.port))
+            .collect();
+        config.host_name = host_name;
+        // `ProxyJump none` in a more specific block disables one set further down
+        if config.proxy_jump.as_deref() == Some("none") {
+            config.proxy_jump = None;
+        }
+        config
+    }
+}
+
+/// `~/.ssh`
+pub fn ssh_dir() -> Option<PathBuf> { BaseDirs::new().map(|x| x.home_dir().join(".ssh")) }
+
+/// Expand a leading `~/` to the home directory.
+pub fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), BaseDirs::new()) {
+        (Some(rest), Some(dirs)) => dirs.home_dir().join(rest).to_string_lossy().to_string(),
+        _ => path.into(),
+    }
+}
+
+/// Expand `~` and the `%h`, `%n`, `%r`, `%p` and `%%` tokens.
+fn expand_tokens(value: &str, host: &str, original_host: &str, user: Option<&str>, port: Option<u16>) -> String {
+    let value = expand_home(value);
+    let mut result = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('h') => result.push_str(host),
+            Some('n') => result.push_str(original_host),
+            Some('r') => result.push_str(user.unwrap_or_default()),
+            Some('p') => result.push_str(&port.unwrap_or(22).to_string()),
+            Some('%') => result.push('%'),
+            Some(other) => {
+                result.push('%');
+                result.push(other);
+            },
+            None => result.push('%'),
+        }
+    }
+    result
+}
+
+/// Split an argument list on whitespace, honoring double quotes.
+fn split_args(value: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in value.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    args.push(std::mem::take(&mut current));
+                }
+            },
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        args.push(current);
+    }
+    args
+}
+
+/// A host matches if any pattern matches and no negated pattern does.
+fn host_matches(patterns: &[String], host: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns {
+        match pattern.strip_prefix('!') {
+            Some(pattern) if wildcard_match(pattern, host) => return false,
+            Some(_) => {},
+            None => matched |= wildcard_match(pattern, host),
+        }
+    }
+    matched
+}
+
+/// Match `*` and `?` wildcards, case-insensitively like OpenSSH host patterns.
+pub(crate) fn wildcard_match(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let value: Vec<char> = value.to_lowercase().chars().collect();
+    let (mut p, mut v) = (0, 0);
+    let mut backtrack = None;
+    while v < value.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == value[v]) {
+            p += 1;
+            v += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, v));
+            p += 1;
+        } else if let Some((bp, bv)) = backtrack {
+            p = bp + 1;
+            v = bv + 1;
+            backtrack = Some((bp, bv + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|x| *x == '*')
+}
+
+/// Resolve an `Include` argument to the files it names. Relative paths are under `~/.ssh`, and the
+/// file name may contain wildcards.
+fn expand_include(arg: &str) -> Vec<PathBuf> {
+    let path = PathBuf::from(expand_home(arg));
+    let path = match (path.is_relative(), ssh_dir()) {
+        (true, Some(dir)) => dir.join(path),
+        _ => path,
+    };
+    let file_pattern = path
+        .file_name()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if !file_pattern.contains(['*', '?']) {
+        return match path.exists() {
+            true => vec![path],
+            false => Vec::new(),
+        };
+    }
+    let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    let mut paths: Vec<PathBuf> = match fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(|x| x.ok())
+            .map(|x| x.path())
+            .filter(|x| x.is_file())
+            .filter(|x| {
+                x.file_name()
+                    .map(|name| wildcard_match(&file_pattern, &name.to_string_lossy()))
+                    .unwrap_or(false)
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    paths.sort();
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn first_match_wins() {
+        let config = SshConfig::parse(
+            "Host dev\n  HostName dev.example.com\n  User eric\n  Port 2222\n\nHost *\n  User root\n  Port 22\n  \
+             StrictHostKeyChecking accept-new\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.query("dev"),
+            HostConfig {
+                host_name: Some("dev.example.com".into()),
+                user: Some("eric".into()),
+                port: Some(2222),
+                strict_host_key_checking: Some(StrictHostKeyChecking::AcceptNew),
+                ..Default::default()
+            }
+        );
+        assert_eq!(config.query("other").user.as_deref(), Some("root"));
+        assert_eq!(config.query("other").host_name, None);
+    }
+
+    #[test]
+    fn keywords_are_case_insensitive_and_take_equals() {
+        let config = SshConfig::parse("HOST dev\nhostname=dev.example.com\nUser = eric\n").unwrap();
+        let host = config.query("dev");
+        assert_eq!(host.host_name.as_deref(), Some("dev.example.com"));
+        assert_eq!(host.user.as_deref(), Some("eric"));
+    }
+
+    #[test]
+    fn identity_files_accumulate() {
+        let config = SshConfig::parse(
+            "Host dev\n  IdentityFile /keys/dev\nHost *\n  IdentityFile /keys/default\n  IdentityFile \"/keys/with space\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.query("dev").identity_files,
+            ["/keys/dev", "/keys/default", "/keys/with space"]
+        );
+        assert_eq!(
+            config.query("other").identity_files,
+            ["/keys/default", "/keys/with space"]
+        );
+    }
+
+    #[test]
+    fn negated_patterns() {
+        let config =
+            SshConfig::parse("Host *.example.com !bastion.example.com\n  ProxyJump bastion.example.com\n").unwrap();
+        assert_eq!(
+            config.query("dev.example.com").proxy_jump.as_deref(),
+            Some("bastion.example.com")
+        );
+        assert_eq!(config.query("bastion.example.com").proxy_jump, None);
+        assert_eq!(config.query("example.org").proxy_jump, None);
+    }
+
+    #[test]
+    fn proxy_jump_none_disables_a_later_one() {
+        let config = SshConfig::parse("Host internal\n  ProxyJump none\nHost *\n  ProxyJump bastion\n").unwrap();
+        assert_eq!(config.query("internal").proxy_jump, None);
+        assert_eq!(config.query("dev").proxy_jump.as_deref(), Some("bastion"));
+    }
+
+    #[test]
+    fn match_blocks_are_skipped() {
+        let config = SshConfig::parse("Match exec true\n  User matched\nHost *\n  User eric\n").unwrap();
+        assert_eq!(config.query("dev").user.as_deref(), Some("eric"));
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("dev?", "dev1"));
+        assert!(!wildcard_match("dev?", "dev"));
+        assert!(wildcard_match("*.EXAMPLE.com", "dev.example.COM"));
+        // the first `*` has to give back characters for the rest of the pattern to match
+        assert!(wildcard_match("*a*b", "aaab"));
+        assert!(wildcard_match("a*b*c", "abxbxc"));
+        assert!(!wildcard_match("a*b*c", "abxbx"));
+        assert!(!wildcard_match("dev", "dev1"));
+    }
+
+    #[test]
+    fn tokens_are_expanded() {
+        let config = SshConfig::parse(
+            "Host dev\n  HostName %h.example.com\n  User eric\n  Port 2222\n  IdentityFile /keys/%h-%n-%r-%p-%%-%x\n",
+        )
+        .unwrap();
+        let host = config.query("dev");
+        assert_eq!(host.host_name.as_deref(), Some("dev.example.com"));
+        assert_eq!(host.identity_files, ["/keys/dev.example.com-dev-eric-2222-%-%x"]);
+        // without a port set it's ssh's default
+        let config = SshConfig::parse("IdentityFile /keys/%p\n").unwrap();
+        assert_eq!(config.query("dev").identity_files, ["/keys/22"]);
+    }
+
+    #[test]
+    fn include_keeps_the_host_scope() {
+        let dir = env::temp_dir().join(format!("stw-ssh-config-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let included = dir.join("included");
+        fs::write(&included, "Host inner\n  HostName inner.example.com\n").unwrap();
+
+        let config = SshConfig::parse(&format!(
+            "Host dev\n  Include {0}\n  Port 2222\nHost *\n  Include {0}\n  User eric\n",
+            included.display()
+        ))
+        .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // options after the Include still belong to the Host block around it
+        assert_eq!(config.query("dev").port, Some(2222));
+        assert_eq!(config.query("inner").port, None);
+        assert_eq!(config.query("other").user.as_deref(), Some("eric"));
+        assert_eq!(config.query("inner").user.as_deref(), Some("eric"));
+        assert_eq!(config.query("inner").host_name.as_deref(), Some("inner.example.com"));
+    }
+}
@@ -0,0 +1,50 @@
+use log::debug;
+use ssh2::Session;
+use std::{
+    net::{SocketAddr, TcpListener},
+    thread,
+};
+
+use crate::ssh::{
+    relay::{Relay, RelayHandle},
+    SshError,
+};
+
+/// A local port forwarded to `host:port` on the remote side, like `ssh -L`. Forwarding stops when it's
+/// dropped.
+pub struct Forward {
+    local_addr: SocketAddr,
+    relay: RelayHandle,
+}
+
+impl Forward {
+    /// Listen on a free loopback port and open a `direct-tcpip` channel to `host:port` for every
+    /// connection to it. Relaying puts the session in non-blocking mode, so run remote commands before
+    /// opening a forward or on another session.
+    pub fn open(session: &Session, host: &str, port: u16) -> Result<Forward, SshError> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let local_addr = listener.local_addr()?;
+        debug!("forwarding {} to remote {} port {}", local_addr, host, port);
+
+        let mut relay = Relay::new(session)?;
+        relay.listen_local(listener, host, port)?;
+        let handle = relay.handle();
+        let host = host.to_string();
+        thread::spawn(move || {
+            if let Err(e) = relay.run() {
+                debug!("forward to {} port {} failed: {}", host, port, e);
+            }
+        });
+        Ok(Forward {
+            local_addr,
+            relay: handle,
+        })
+    }
+
+    /// Local address to connect to.
+    pub fn local_addr(&self) -> SocketAddr { self.local_addr }
+}
+
+impl Drop for Forward {
+    fn drop(&mut self) { self.relay.stop(); }
+}
@@ -0,0 +1,40 @@
+use log::debug;
+use ssh2::Session;
+use std::{
+    net::{TcpListener, TcpStream},
+    thread,
+};
+
+use crate::ssh::{relay::Relay, SshError};
+
+/// Open a `direct-tcpip` channel from a jump host to `host:port` and expose it as a local socket, so
+/// the next session can run its handshake over it. The jump session is kept alive by the thread
+/// relaying data until both sides close.
+pub fn tunnel(session: Session, host: &str, port: u16) -> Result<TcpStream, SshError> {
+    debug!("opening jump channel to {} port {}", host, port);
+    let channel = session.channel_direct_tcpip(host, port, None)?;
+
+    // libssh2 needs a real socket, so bridge the channel through a loopback connection
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let local = TcpStream::connect(listener.local_addr()?)?;
+    let relay_stream = loop {
+        let (stream, peer) = listener.accept()?;
+        // make sure another local process didn't connect first
+        if peer == local.local_addr()? {
+            break stream;
+        }
+    };
+
+    let mut relay = Relay::new(&session)?;
+    relay.add(
+        channel,
+        relay_stream,
+        &format!("jump channel to {} port {}", host, port),
+    )?;
+    thread::spawn(move || {
+        if let Err(e) = relay.run() {
+            debug!("jump channel closed: {}", e);
+        }
+    });
+    Ok(local)
+}
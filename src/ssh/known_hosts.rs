@@ -0,0 +1,160 @@
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use ssh2::{CheckResult, HostKeyType, KnownHostFileKind, KnownHosts, Session};
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::ssh::{config::ssh_dir, SshError};
+
+/// What to do with host keys that aren't in `known_hosts`, with the same meaning as OpenSSH's
+/// `StrictHostKeyChecking`. A changed host key is always refused unless checking is disabled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StrictHostKeyChecking {
+    /// Refuse unknown hosts
+    Yes,
+    /// Don't check host keys at all
+    No,
+    /// Add unknown hosts to `known_hosts` without asking
+    AcceptNew,
+    /// Show the fingerprint of unknown hosts and ask before trusting them
+    #[default]
+    Ask,
+}
+
+impl StrictHostKeyChecking {
+    /// Parse an ssh_config value.
+    pub fn from_ssh_config(value: &str) -> Option<StrictHostKeyChecking> {
+        match value.to_lowercase().as_str() {
+            "yes" => Some(StrictHostKeyChecking::Yes),
+            "no" | "off" => Some(StrictHostKeyChecking::No),
+            "accept-new" => Some(StrictHostKeyChecking::AcceptNew),
+            "ask" => Some(StrictHostKeyChecking::Ask),
+            _ => None,
+        }
+    }
+}
+
+/// Check the session's host key against `~/.ssh/known_hosts` and `/etc/ssh/ssh_known_hosts`.
+pub fn verify_host_key(session: &Session, host: &str, port: u16, mode: StrictHostKeyChecking) -> Result<(), SshError> {
+    let (key, key_type) = session.host_key().ok_or_else(|| SshError::HostKey(host.into()))?;
+    let fingerprint = fingerprint(session);
+    let entry = match port {
+        22 => host.to_string(),
+        _ => format!("[{}]:{}", host, port),
+    };
+
+    let mut known_hosts = session.known_hosts()?;
+    for file in known_hosts_files() {
+        read_known_hosts(&mut known_hosts, &file);
+    }
+
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => {
+            debug!("host key for {} found in known_hosts", entry);
+            Ok(())
+        },
+        CheckResult::Mismatch if mode == StrictHostKeyChecking::No => {
+            warn!(
+                "host key for {} has changed ({}), continuing anyway",
+                entry, fingerprint
+            );
+            Ok(())
+        },
+        CheckResult::Mismatch => Err(SshError::HostKeyMismatch {
+            host: entry,
+            fingerprint,
+        }),
+        CheckResult::NotFound | CheckResult::Failure => match mode {
+            StrictHostKeyChecking::No => Ok(()),
+            StrictHostKeyChecking::Yes => Err(SshError::UnknownHostKey(entry)),
+            StrictHostKeyChecking::AcceptNew => {
+                println!("Permanently added {} ({}) to known_hosts", entry, fingerprint);
+                add_known_host(session, &entry, key, key_type)
+            },
+            StrictHostKeyChecking::Ask => {
+                println!("The authenticity of host {} can't be established.", entry);
+                println!("{} key fingerprint is {}.", key_type_name(key_type), fingerprint);
+                println!("Are you sure you want to continue connecting (yes/no)? ");
+                let mut answer = String::new();
+                io::stdin().read_line(&mut answer)?;
+                match answer.trim().to_lowercase().as_str() {
+                    "yes" | "y" => add_known_host(session, &entry, key, key_type),
+                    _ => Err(SshError::HostKeyRejected(entry)),
+                }
+            },
+        },
+    }
+}
+
+/// SHA256 fingerprint of the session's host key, formatted like OpenSSH.
+pub fn fingerprint(session: &Session) -> String {
+    match session.host_key() {
+        Some((key, _)) => key_fingerprint(key),
+        None => "unknown".into(),
+    }
+}
+
+/// SHA256 fingerprint of a public key blob, formatted like OpenSSH.
+pub fn key_fingerprint(blob: &[u8]) -> String { format!("SHA256:{}", STANDARD_NO_PAD.encode(Sha256::digest(blob))) }
+
+fn known_hosts_files() -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = ssh_dir().map(|x| x.join("known_hosts")).into_iter().collect();
+    files.push(PathBuf::from("/etc/ssh/ssh_known_hosts"));
+    files
+}
+
+/// Read known hosts line by line, libssh2 gives up on the whole file if it finds one key type it
+/// doesn't support.
+fn read_known_hosts(known_hosts: &mut KnownHosts, file: &Path) {
+    let contents = match fs::read_to_string(file) {
+        Ok(contents) => contents,
+        Err(_) => return,
+    };
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Err(e) = known_hosts.read_str(line, KnownHostFileKind::OpenSSH) {
+            debug!("skipping known_hosts line in {:#?}: {}", file, e);
+        }
+    }
+}
+
+/// Append a host key to `~/.ssh/known_hosts`.
+fn add_known_host(session: &Session, entry: &str, key: &[u8], key_type: HostKeyType) -> Result<(), SshError> {
+    let ssh_dir = ssh_dir().ok_or_else(|| SshError::HostKey(entry.into()))?;
+    fs::create_dir_all(&ssh_dir)?;
+
+    // render the line with a fresh set so only the new host is written
+    let mut known_hosts = session.known_hosts()?;
+    known_hosts.add(entry, key, "", key_type.into())?;
+    let host = known_hosts
+        .hosts()?
+        .into_iter()
+        .next()
+        .ok_or_else(|| SshError::HostKey(entry.into()))?;
+    let line = known_hosts.write_string(&host, KnownHostFileKind::OpenSSH)?;
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(ssh_dir.join("known_hosts"))?;
+    writeln!(file, "{}", line.trim_end())?;
+    Ok(())
+}
+
+fn key_type_name(key_type: HostKeyType) -> &'static str {
+    match key_type {
+        HostKeyType::Rsa => "RSA",
+        HostKeyType::Dss => "DSA",
+        HostKeyType::Ecdsa256 | HostKeyType::Ecdsa384 | HostKeyType::Ecdsa521 => "ECDSA",
+        HostKeyType::Ed255219 => "ED25519",
+        HostKeyType::Unknown => "Unknown",
+    }
+}
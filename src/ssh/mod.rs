@@ -1,63 +1,366 @@
-use rpassword::read_password;
-use ssh2::Session;
-use std::{net::TcpStream, path::Path};
+use log::debug;
+use ssh2::{Channel, ErrorCode, Session};
+use std::{
+    env,
+    io::{self, ErrorKind, Read, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
 use thiserror::Error;
 
+use crate::ssh::{
+    auth::authenticate,
+    config::SshConfig,
+    known_hosts::{verify_host_key, StrictHostKeyChecking},
+    relay::SessionWaiter,
+};
+
+/// ssh user authentication
+pub mod auth;
+/// OpenSSH client config parsing
+pub mod config;
+/// Local port forwards over ssh
+pub mod forward;
+/// Jump host tunnels
+pub mod jump;
+/// Host key verification against known_hosts
+pub mod known_hosts;
+/// Event-driven relaying between ssh channels and local sockets
+pub mod relay;
+/// Interactive remote shells
+pub mod shell;
+
 #[derive(Error, Debug)]
 pub enum SshError {
     #[error("ssh error")]
     Ssh(#[from] ssh2::Error),
     #[error("network error")]
     Io(#[from] std::io::Error),
+    #[error("invalid remote address `{0}`")]
+    Address(String),
+    #[error("couldn't read host key for {0}")]
+    HostKey(String),
+    #[error("no host key for {0} in known_hosts and strict host key checking is enabled")]
+    UnknownHostKey(String),
+    #[error("host key for {host} has changed, possible man-in-the-middle attack (offered {fingerprint})")]
+    HostKeyMismatch { host: String, fingerprint: String },
+    #[error("host key for {0} wasn't accepted")]
+    HostKeyRejected(String),
+    #[error("authentication failed, server accepts {0}")]
+    Auth(String),
     //#[error("Couldn't find config directory")]
     //NotFound,
 }
 
-pub fn create_session(host: &str, user: &str, key: Option<&impl AsRef<Path>>) -> Result<Session, SshError> {
-    let tcp = TcpStream::connect(format!("{}:22", host))?;
+/// Split `host:port` or `[ipv6]:port` into host and port. Bare IPv6 addresses have no port.
+pub fn parse_address(address: &str) -> Result<(String, Option<u16>), SshError> {
+    let parse_port = |port: &str| port.parse::<u16>().map_err(|_| SshError::Address(address.into()));
+    if let Some(rest) = address.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']').ok_or_else(|| SshError::Address(address.into()))?;
+        return match rest {
+            "" => Ok((host.into(), None)),
+            _ => match rest.strip_prefix(':') {
+                Some(port) => Ok((host.into(), Some(parse_port(port)?))),
+                None => Err(SshError::Address(address.into())),
+            },
+        };
+    }
+    match address.matches(':').count() {
+        0 => Ok((address.into(), None)),
+        1 => {
+            let (host, port) = address.split_once(':').unwrap();
+            Ok((host.into(), Some(parse_port(port)?)))
+        },
+        // more than one colon without brackets is a bare IPv6 address
+        _ => Ok((address.into(), None)),
+    }
+}
+
+/// Host to connect to. Anything left unset is filled in from `~/.ssh/config`.
+#[derive(Clone, Debug, Default)]
+pub struct Target {
+    /// Host name, address or `~/.ssh/config` alias
+    pub host: String,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    pub key: Option<PathBuf>,
+    pub strict_host_key_checking: Option<StrictHostKeyChecking>,
+    /// Jump hosts to connect through, in order, as `[user@]host[:port]`. Defaults to `ProxyJump`.
+    pub jump_hosts: Vec<String>,
+}
+
+impl Target {
+    /// Parse a `[ssh://][user@]host[:port]` jump host spec.
+    pub fn parse(spec: &str) -> Result<Target, SshError> {
+        let spec = spec.trim();
+        let spec = spec.strip_prefix("ssh://").unwrap_or(spec);
+        let (user, address) = match spec.rsplit_once('@') {
+            Some((user, address)) => (Some(user.to_string()), address),
+            None => (None, spec),
+        };
+        let (host, port) = parse_address(address)?;
+        Ok(Target {
+            host,
+            port,
+            user,
+            ..Default::default()
+        })
+    }
+
+    /// Apply the matching `~/.ssh/config` options. Values already set take precedence, as they would on
+    /// the ssh command line.
+    pub fn resolve(&self, ssh_config: &SshConfig) -> Target {
+        let host_config = ssh_config.query(&self.host);
+        Target {
+            host: host_config.host_name.unwrap_or_else(|| self.host.clone()),
+            port: self.port.or(host_config.port),
+            user: self.user.clone().or(host_config.user),
+            key: self.key.clone().or_else(|| {
+                host_config
+                    .identity_files
+                    .iter()
+                    .map(PathBuf::from)
+                    .find(|x| x.exists())
+            }),
+            strict_host_key_checking: self.strict_host_key_checking.or(host_config.strict_host_key_checking),
+            jump_hosts: match (self.jump_hosts.is_empty(), host_config.proxy_jump) {
+                (true, Some(proxy_jump)) => proxy_jump.split(',').map(String::from).collect(),
+                _ => self.jump_hosts.clone(),
+            },
+        }
+    }
+}
+
+/// Connect and authenticate to `target`, going through its jump hosts if it has any.
+pub fn create_session(target: &Target) -> Result<Session, SshError> {
+    let ssh_config = SshConfig::load_default()?;
+    let target = target.resolve(&ssh_config);
+
+    let mut hops = Vec::new();
+    for jump_host in &target.jump_hosts {
+        let mut hop = Target::parse(jump_host)?.resolve(&ssh_config);
+        // the chain is spelled out explicitly, ignore the jump hosts' own ProxyJump
+        hop.jump_hosts = Vec::new();
+        hop.strict_host_key_checking = hop.strict_host_key_checking.or(target.strict_host_key_checking);
+        hops.push(hop);
+    }
+    hops.push(target);
+
+    let mut stream = None;
+    for (hop, next) in hops.iter().zip(hops.iter().skip(1)) {
+        let session = open_session(hop, stream.take())?;
+        stream = Some(jump::tunnel(session, &next.host, next.port.unwrap_or(22))?);
+    }
+    open_session(hops.last().unwrap(), stream)
+}
+
+/// Open an ssh session to a resolved target over `stream`, or a new connection if there isn't one.
+fn open_session(target: &Target, stream: Option<TcpStream>) -> Result<Session, SshError> {
+    let host = target.host.as_str();
+    let port = target.port.unwrap_or(22);
+    let user = match &target.user {
+        Some(user) => user.clone(),
+        None => env::var("USER").or_else(|_| env::var("USERNAME")).unwrap_or_default(),
+    };
+    let user = user.as_str();
+    let key = target.key.as_ref();
+    debug!("connecting to {}@{} port {}", user, host, port);
+    let tcp = match stream {
+        Some(stream) => stream,
+        None => TcpStream::connect((host, port))?,
+    };
     let mut sess = Session::new()?;
     sess.set_tcp_stream(tcp);
     sess.handshake()?;
+    verify_host_key(&sess, host, port, target.strict_host_key_checking.unwrap_or_default())?;
 
-    let mut agent = sess.agent()?;
-
-    if agent.connect().is_ok() {
-        match key {
-            Some(key) => {
-                let key = key.as_ref().to_string_lossy().to_string();
-                agent.list_identities()?;
-                for identity in &agent.identities()? {
-                    if identity.comment() == key {
-                        agent.userauth(user, identity)?
-                    }
-                }
-            },
-            None => {
-                sess.userauth_agent(user)?;
-            },
+    authenticate(&sess, user, key)?;
+    Ok(sess)
+}
+
+/// Run a command on the remote host, returning its stdout and exit status.
+pub fn exec(session: &Session, command: &str) -> Result<(String, i32), SshError> {
+    debug!("running remote command `{}`", command);
+    let mut channel = session.channel_session()?;
+    channel.exec(command)?;
+    let mut s = String::new();
+    channel.read_to_string(&mut s)?;
+    channel.wait_close()?;
+    Ok((s, channel.exit_status()?))
+}
+
+/// Quote `arg` for a POSIX shell, so it's passed on as one word with nothing in it expanded.
+pub(crate) fn shell_quote(arg: &str) -> String { format!("'{}'", arg.replace('\'', r"'\''")) }
+
+/// SHA-256 of a remote file as lowercase hex, or None if it doesn't exist or can't be hashed.
+pub fn remote_sha256(session: &Session, path: &str) -> Result<Option<String>, SshError> {
+    // sha256sum on Linux, shasum on macOS and the BSDs
+    let (output, code) = exec(
+        session,
+        &format!("sha256sum {0:#?} 2>/dev/null || shasum -a 256 {0:#?} 2>/dev/null", path),
+    )?;
+    if code != 0 {
+        return Ok(None);
+    }
+    Ok(output.split_whitespace().next().map(str::to_lowercase))
+}
+
+/// Upload `data` to `path` on the remote host with the given file mode.
+pub fn upload(session: &Session, path: &Path, mode: i32, data: &[u8]) -> Result<(), SshError> {
+    debug!("uploading {} bytes to {:#?}", data.len(), path);
+    let mut remote_file = session.scp_send(path, mode, data.len() as u64, None)?;
+    remote_file.write_all(data)?;
+    remote_file.send_eof()?;
+    remote_file.wait_eof()?;
+    remote_file.close()?;
+    remote_file.wait_close()?;
+    Ok(())
+}
+
+/// Run `command` in a PTY, copying its output to the terminal as it arrives, and return its exit status.
+/// Leaves the session in non-blocking mode, like a relay does.
+pub fn run_pty(session: &Session, command: &str) -> Result<i32, SshError> {
+    debug!("running remote command `{}` in a pty", command);
+    let mut channel = retry(|| session.channel_session())?;
+    let term = env::var("TERM").unwrap_or_else(|_| "xterm".into());
+    retry(|| channel.request_pty(&term, None, None))?;
+    retry(|| channel.exec(command))?;
+
+    let waiter = SessionWaiter::new(session)?;
+    let (mut stdout, mut stderr) = (io::stdout(), io::stderr());
+    let mut buf = [0_u8; 16384];
+    loop {
+        let mut progressed = false;
+        for (stream_id, output) in [(0, &mut stdout as &mut dyn Write), (1, &mut stderr)] {
+            match channel.stream(stream_id).read(&mut buf) {
+                Ok(0) => {},
+                Ok(amount) => {
+                    output.write_all(&buf[..amount])?;
+                    output.flush()?;
+                    progressed = true;
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {},
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if channel.eof() {
+            break;
+        }
+        if !progressed {
+            waiter.wait(WAIT_TIMEOUT)?;
         }
     }
-    if !sess.authenticated() {
-        match key {
-            Some(key) => {
-                let pubkey_location = format!("{:#?}.pub", key.as_ref());
-                let pubkey = Path::new(&pubkey_location);
-                let pubkey = match pubkey.exists() {
-                    true => Some(pubkey),
-                    false => None,
-                };
-                if sess.userauth_pubkey_file(user, pubkey, key.as_ref(), None).is_err() {
-                    println!("Type ssh key password: ");
-                    let password = read_password()?;
-                    sess.userauth_pubkey_file(user, pubkey, key.as_ref(), Some(&password))?
-                }
-            },
-            None => {
-                println!("Type ssh password: ");
-                let password = read_password()?;
-                sess.userauth_password(user, &password)?;
-            },
+    retry(|| channel.wait_close())?;
+    exit_code(&channel)
+}
+
+/// Exit status of a closed channel. A command killed by a signal has no exit status, so it's reported
+/// like a shell would, as 128 plus the signal number.
+pub(crate) fn exit_code(channel: &Channel) -> Result<i32, SshError> {
+    let signal = match channel.exit_signal()?.exit_signal {
+        Some(signal) => signal,
+        None => return Ok(channel.exit_status()?),
+    };
+    eprintln!("Remote process killed by SIG{}", signal);
+    // the signals RFC 4254 names, numbered as on Linux
+    let number = match signal.as_str() {
+        "HUP" => 1,
+        "INT" => 2,
+        "QUIT" => 3,
+        "ILL" => 4,
+        "ABRT" => 6,
+        "FPE" => 8,
+        "KILL" => 9,
+        "USR1" => 10,
+        "SEGV" => 11,
+        "USR2" => 12,
+        "PIPE" => 13,
+        "ALRM" => 14,
+        "TERM" => 15,
+        _ => return Ok(255),
+    };
+    Ok(128 + number)
+}
+
+/// Longest a non-blocking loop sleeps on the session socket, in case libssh2 buffered data without it
+/// becoming readable again.
+const WAIT_TIMEOUT: Duration = Duration::from_secs(1);
+/// libssh2's `LIBSSH2_ERROR_EAGAIN`
+const EAGAIN: i32 = -37;
+
+/// Repeat a libssh2 call for as long as a non-blocking session asks to try again.
+pub(crate) fn retry<T>(mut call: impl FnMut() -> Result<T, ssh2::Error>) -> Result<T, ssh2::Error> {
+    loop {
+        match call() {
+            Err(ref e) if is_eagain(e) => thread::sleep(Duration::from_millis(1)),
+            result => return result,
         }
     }
-    Ok(sess)
+}
+
+/// A non-blocking session would have blocked.
+pub(crate) fn is_eagain(error: &ssh2::Error) -> bool { error.code() == ErrorCode::Session(EAGAIN) }
+
+/// Download a file from the remote host, or None if it doesn't exist.
+pub fn download(session: &Session, path: &Path) -> Result<Option<Vec<u8>>, SshError> {
+    let (_, code) = exec(session, &format!("test -f {:#?}", path))?;
+    if code != 0 {
+        return Ok(None);
+    }
+    debug!("downloading {:#?}", path);
+    let (mut remote_file, _) = session.scp_recv(path)?;
+    let mut data = Vec::new();
+    remote_file.read_to_end(&mut data)?;
+    remote_file.send_eof()?;
+    remote_file.wait_eof()?;
+    remote_file.close()?;
+    remote_file.wait_close()?;
+    Ok(Some(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_addresses() {
+        let parsed = |address| parse_address(address).unwrap();
+        assert_eq!(parsed("example.com"), ("example.com".into(), None));
+        assert_eq!(parsed("example.com:2222"), ("example.com".into(), Some(2222)));
+        assert_eq!(parsed("192.168.1.2:22"), ("192.168.1.2".into(), Some(22)));
+        assert_eq!(parsed("[::1]:2222"), ("::1".into(), Some(2222)));
+        assert_eq!(parsed("[fe80::1%eth0]"), ("fe80::1%eth0".into(), None));
+        assert_eq!(parsed("::1"), ("::1".into(), None));
+        assert_eq!(parsed("2001:db8::1"), ("2001:db8::1".into(), None));
+    }
+
+    #[test]
+    fn reject_bad_addresses() {
+        for address in [
+            "example.com:",
+            "example.com:ssh",
+            "example.com:65536",
+            "example.com:-1",
+            "[::1]:",
+            "[::1]:x",
+            "[::1]2222",
+            "[::1",
+        ] {
+            assert!(
+                matches!(parse_address(address), Err(SshError::Address(x)) if x == address),
+                "{}",
+                address
+            );
+        }
+    }
+
+    #[test]
+    fn shell_quote_keeps_one_word() {
+        assert_eq!(shell_quote("/home/eric/code"), "'/home/eric/code'");
+        assert_eq!(shell_quote("a b $HOME `id` \\n"), "'a b $HOME `id` \\n'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
 }
@@ -0,0 +1,433 @@
+use log::{debug, warn};
+use polling::{Event, Events, Poller};
+use ssh2::{BlockDirections, Channel, Listener, Session};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, BorrowedFd};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, BorrowedSocket};
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::ssh::{is_eagain, SshError};
+
+/// Poller key of the ssh socket.
+const SESSION: usize = 0;
+/// Poller key of the local listener.
+const LOCAL_LISTENER: usize = 1;
+/// Poller key of the first relayed connection.
+const FIRST_CONNECTION: usize = 2;
+const BUFFER_SIZE: usize = 32768;
+/// libssh2 can queue data for a channel while reading for another, without the socket becoming readable
+/// again. Waking up now and then makes sure such data isn't stuck when several relays share a session.
+const POLL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Copies data between ssh channels and local sockets from a single thread, sleeping in the poller until
+/// the ssh socket or one of the local sockets is ready. Connections come from a local listener
+/// (`direct-tcpip` channels, like `ssh -L`), a remote listener (like `ssh -R`) or are added directly.
+pub struct Relay {
+    session: Session,
+    poller: Arc<Poller>,
+    stop: Arc<AtomicBool>,
+    local_listener: Option<(TcpListener, String, u16)>,
+    remote_listener: Option<(Listener, SocketAddr)>,
+    /// Local connections waiting for their channel to open
+    pending: Vec<(TcpStream, SocketAddr)>,
+    connections: HashMap<usize, Connection>,
+    /// Channels that are closed but not acknowledged by the server yet
+    closing: Vec<Channel>,
+    next_key: usize,
+}
+
+/// Stops a running `Relay` from another thread.
+#[derive(Clone)]
+pub struct RelayHandle {
+    poller: Arc<Poller>,
+    stop: Arc<AtomicBool>,
+}
+
+/// A channel relayed to a local socket. Each direction is closed on its own once its sender is done, so
+/// protocols that half-close, like a request followed by `shutdown(Write)`, still get their response.
+struct Connection {
+    /// Where the connection goes, for logging
+    context: String,
+    channel: Channel,
+    stream: TcpStream,
+    to_channel: Vec<u8>,
+    to_stream: Vec<u8>,
+    stream_eof: bool,
+    channel_eof: bool,
+    /// The stream's EOF has been passed on to the channel
+    eof_sent: bool,
+    /// The channel's EOF has been passed on to the stream
+    shut_down: bool,
+}
+
+impl Relay {
+    /// Put the session in non-blocking mode and watch its socket.
+    pub fn new(session: &Session) -> Result<Relay, SshError> {
+        let poller = Poller::new()?;
+        session.set_blocking(false);
+        // SAFETY: the session, and with it the socket, lives as long as the relay, which removes it
+        // from the poller when it's dropped
+        unsafe { poller.add(&session_source(session), Event::readable(SESSION))? };
+        Ok(Relay {
+            session: session.clone(),
+            poller: Arc::new(poller),
+            stop: Arc::new(AtomicBool::new(false)),
+            local_listener: None,
+            remote_listener: None,
+            pending: Vec::new(),
+            connections: HashMap::new(),
+            closing: Vec::new(),
+            next_key: FIRST_CONNECTION,
+        })
+    }
+
+    /// Open a `direct-tcpip` channel to `host:port` for every connection accepted on `listener`.
+    pub fn listen_local(&mut self, listener: TcpListener, host: &str, port: u16) -> Result<(), SshError> {
+        listener.set_nonblocking(true)?;
+        // SAFETY: the listener is owned by the relay and removed from the poller when it's dropped
+        unsafe { self.poller.add(&listener, Event::readable(LOCAL_LISTENER))? };
+        self.local_listener = Some((listener, host.into(), port));
+        Ok(())
+    }
+
+    /// Connect every channel accepted on a remote forward listener to `target`.
+    pub fn listen_remote(&mut self, listener: Listener, target: SocketAddr) {
+        self.remote_listener = Some((listener, target));
+    }
+
+    /// Relay between an already open channel and local socket. `context` describes the connection in logs.
+    pub fn add(&mut self, channel: Channel, stream: TcpStream, context: &str) -> Result<(), SshError> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        let key = self.next_key;
+        self.next_key += 1;
+        // SAFETY: the stream is owned by the connection and removed from the poller before it's dropped
+        unsafe { self.poller.add(&stream, Event::none(key))? };
+        debug!("{}: open", context);
+        self.connections.insert(
+            key,
+            Connection {
+                context: context.into(),
+                channel,
+                stream,
+                to_channel: Vec::new(),
+                to_stream: Vec::new(),
+                stream_eof: false,
+                channel_eof: false,
+                eof_sent: false,
+                shut_down: false,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn handle(&self) -> RelayHandle {
+        RelayHandle {
+            poller: self.poller.clone(),
+            stop: self.stop.clone(),
+        }
+    }
+
+    /// Relay until stopped through a `RelayHandle`, the session fails or, without listeners, the last
+    /// connection closes.
+    pub fn run(&mut self) -> Result<(), SshError> {
+        let mut events = Events::new();
+        let mut buf = vec![0_u8; BUFFER_SIZE];
+        while !self.stop.load(Ordering::SeqCst) {
+            // one connection's read can queue data for another, so go round until nothing moves
+            while self.pump(&mut buf)? {}
+            if self.local_listener.is_none() && self.remote_listener.is_none() && self.connections.is_empty() {
+                break;
+            }
+            self.arm()?;
+            events.clear();
+            self.poller.wait(&mut events, Some(POLL_TIMEOUT))?;
+        }
+        Ok(())
+    }
+
+    /// Accept new connections and move whatever data is ready. Returns true if anything happened.
+    fn pump(&mut self, buf: &mut [u8]) -> Result<bool, SshError> {
+        let mut progressed = self.accept()?;
+
+        let mut done = Vec::new();
+        for (key, connection) in &mut self.connections {
+            match connection.pump(buf) {
+                Ok(moved) => progressed |= moved,
+                Err(e) => {
+                    warn!("{}: {}", connection.context, e);
+                    done.push(*key);
+                    continue;
+                },
+            }
+            if connection.done() {
+                debug!("{}: closed", connection.context);
+                done.push(*key);
+            }
+        }
+        for key in done {
+            if let Some(connection) = self.connections.remove(&key) {
+                let _ = self.poller.delete(&connection.stream);
+                self.closing.push(connection.channel);
+                progressed = true;
+            }
+        }
+
+        self.closing
+            .retain_mut(|channel| matches!(channel.close(), Err(ref e) if is_eagain(e)));
+        Ok(progressed)
+    }
+
+    fn accept(&mut self) -> Result<bool, SshError> {
+        let mut progressed = false;
+
+        let mut accepted = Vec::new();
+        if let Some((listener, target)) = &mut self.remote_listener {
+            loop {
+                match listener.accept() {
+                    Ok(channel) => accepted.push((channel, *target)),
+                    Err(ref e) if is_eagain(e) => break,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        for (channel, target) in accepted {
+            progressed = true;
+            let context = format!("forwarded connection to {}", target);
+            match TcpStream::connect(target) {
+                Ok(stream) => self.add(channel, stream, &context)?,
+                Err(e) => {
+                    warn!("{}: couldn't connect: {}", context, e);
+                    self.closing.push(channel);
+                },
+            }
+        }
+
+        if let Some((listener, _, _)) = &self.local_listener {
+            loop {
+                match listener.accept() {
+                    Ok(accepted) => self.pending.push(accepted),
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e.into()),
+                }
+                progressed = true;
+            }
+        }
+        while let Some((stream, peer)) = self.pending.pop() {
+            let (_, host, port) = self
+                .local_listener
+                .as_ref()
+                .expect("pending connections need a listener");
+            let context = format!("connection from {} to remote {} port {}", peer, host, port);
+            match self.session.channel_direct_tcpip(host, *port, None) {
+                Ok(channel) => self.add(channel, stream, &context)?,
+                Err(ref e) if is_eagain(e) => {
+                    self.pending.push((stream, peer));
+                    break;
+                },
+                // dropping the stream closes it, so the client sees the failure too
+                Err(e) => warn!("{}: couldn't open a channel: {}", context, e),
+            }
+            progressed = true;
+        }
+        Ok(progressed)
+    }
+
+    /// Register interest in whatever would let a stalled transfer continue. A channel whose window is used
+    /// up waits for the server to adjust it, so only libssh2 knows whether the socket needs to be writable.
+    fn arm(&self) -> Result<(), SshError> {
+        let session_writable = matches!(
+            self.session.block_directions(),
+            BlockDirections::Outbound | BlockDirections::Both
+        );
+        self.poller.modify(
+            session_source(&self.session),
+            Event::new(SESSION, true, session_writable),
+        )?;
+        if let Some((listener, _, _)) = &self.local_listener {
+            self.poller.modify(listener, Event::readable(LOCAL_LISTENER))?;
+        }
+        for (key, connection) in &self.connections {
+            let readable = connection.to_channel.is_empty() && !connection.stream_eof;
+            let writable = !connection.to_stream.is_empty();
+            self.poller
+                .modify(&connection.stream, Event::new(*key, readable, writable))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        let _ = self.poller.delete(session_source(&self.session));
+        if let Some((listener, _, _)) = &self.local_listener {
+            let _ = self.poller.delete(listener);
+        }
+        for connection in self.connections.values() {
+            let _ = self.poller.delete(&connection.stream);
+        }
+    }
+}
+
+impl RelayHandle {
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.poller.notify();
+    }
+}
+
+impl Connection {
+    /// Move data both ways until neither side is ready, passing on EOFs once everything before them has
+    /// been sent. Returns true if anything moved.
+    fn pump(&mut self, buf: &mut [u8]) -> Result<bool, SshError> {
+        let mut progressed = false;
+        loop {
+            let mut moved = false;
+
+            if self.to_channel.is_empty() && !self.stream_eof {
+                match self.stream.read(buf) {
+                    Ok(0) => self.stream_eof = true,
+                    Ok(amount) => self.to_channel.extend_from_slice(&buf[..amount]),
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => {},
+                    Err(e) => return Err(e.into()),
+                }
+                moved |= !self.to_channel.is_empty() || self.stream_eof;
+            }
+            if !self.to_channel.is_empty() {
+                match self.channel.write(&self.to_channel) {
+                    Ok(amount) => {
+                        self.to_channel.drain(..amount);
+                        moved |= amount > 0;
+                    },
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => {},
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            if self.stream_eof && self.to_channel.is_empty() && !self.eof_sent {
+                match self.channel.send_eof() {
+                    Ok(()) => {
+                        self.eof_sent = true;
+                        moved = true;
+                    },
+                    Err(ref e) if is_eagain(e) => {},
+                    Err(e) => return Err(e.into()),
+                }
+            }
+
+            if self.to_stream.is_empty() && !self.channel_eof {
+                match self.channel.read(buf) {
+                    Ok(0) => self.channel_eof = self.channel.eof(),
+                    Ok(amount) => self.to_stream.extend_from_slice(&buf[..amount]),
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => {},
+                    Err(e) => return Err(e.into()),
+                }
+                moved |= !self.to_stream.is_empty() || self.channel_eof;
+            }
+            if !self.to_stream.is_empty() {
+                match self.stream.write(&self.to_stream) {
+                    Ok(amount) => {
+                        self.to_stream.drain(..amount);
+                        moved |= amount > 0;
+                    },
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => {},
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            if self.channel_eof && self.to_stream.is_empty() && !self.shut_down {
+                match self.stream.shutdown(Shutdown::Write) {
+                    Ok(()) => {},
+                    // the peer closed already, there's nobody left to tell
+                    Err(ref e) if e.kind() == ErrorKind::NotConnected => {},
+                    Err(e) => return Err(e.into()),
+                }
+                self.shut_down = true;
+                moved = true;
+            }
+
+            if !moved || self.done() {
+                return Ok(progressed);
+            }
+            progressed = true;
+        }
+    }
+
+    /// Both sides have closed and their EOFs have been passed on.
+    fn done(&self) -> bool { self.eof_sent && self.shut_down }
+}
+
+/// Sleeps until the socket of a non-blocking session is ready for what libssh2 is waiting on, for loops
+/// over a session's channels that would otherwise spin on `WouldBlock`.
+pub(crate) struct SessionWaiter {
+    session: Session,
+    poller: Arc<Poller>,
+}
+
+/// Wakes a `SessionWaiter` from another thread.
+#[derive(Clone)]
+pub(crate) struct Waker {
+    poller: Arc<Poller>,
+}
+
+impl SessionWaiter {
+    /// Put the session in non-blocking mode and watch its socket.
+    pub(crate) fn new(session: &Session) -> Result<SessionWaiter, SshError> {
+        let poller = Poller::new()?;
+        session.set_blocking(false);
+        // SAFETY: the waiter keeps the session, and with it the socket, alive and removes it from the
+        // poller when it's dropped
+        unsafe { poller.add(&session_source(session), Event::none(SESSION))? };
+        Ok(SessionWaiter {
+            session: session.clone(),
+            poller: Arc::new(poller),
+        })
+    }
+
+    /// Wait until the socket is ready for what libssh2 is blocked on, `timeout` passes or a `Waker` is
+    /// woken.
+    pub(crate) fn wait(&self, timeout: Duration) -> Result<(), SshError> {
+        let writable = matches!(
+            self.session.block_directions(),
+            BlockDirections::Outbound | BlockDirections::Both
+        );
+        self.poller
+            .modify(session_source(&self.session), Event::new(SESSION, true, writable))?;
+        self.poller.wait(&mut Events::new(), Some(timeout))?;
+        Ok(())
+    }
+
+    pub(crate) fn waker(&self) -> Waker {
+        Waker {
+            poller: self.poller.clone(),
+        }
+    }
+}
+
+impl Drop for SessionWaiter {
+    fn drop(&mut self) { let _ = self.poller.delete(session_source(&self.session)); }
+}
+
+impl Waker {
+    pub(crate) fn wake(&self) { let _ = self.poller.notify(); }
+}
+
+#[cfg(unix)]
+fn session_source(session: &Session) -> BorrowedFd<'_> {
+    // SAFETY: the session keeps the socket open for as long as it's borrowed
+    unsafe { BorrowedFd::borrow_raw(session.as_raw_fd()) }
+}
+
+#[cfg(windows)]
+fn session_source(session: &Session) -> BorrowedSocket<'_> {
+    // SAFETY: the session keeps the socket open for as long as it's borrowed
+    unsafe { BorrowedSocket::borrow_raw(session.as_raw_socket()) }
+}
@@ -0,0 +1,124 @@
+use crossterm::terminal;
+use log::debug;
+use ssh2::Session;
+use std::{
+    env,
+    io::{self, ErrorKind, Read, Write},
+    sync::mpsc::{self, TryRecvError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::ssh::{exit_code, relay::SessionWaiter, retry, shell_quote, SshError};
+
+/// How often the terminal size is checked and passed on to the remote pty.
+const RESIZE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Restores the terminal when dropped, even if the shell fails.
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> Result<RawMode, SshError> {
+        terminal::enable_raw_mode()?;
+        Ok(RawMode)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) { let _ = terminal::disable_raw_mode(); }
+}
+
+/// Open an interactive login shell on the remote, in `dir` if it's set, and return its exit status.
+pub fn interactive_shell(session: &Session, dir: Option<&str>) -> Result<i32, SshError> {
+    let mut channel = retry(|| session.channel_session())?;
+    let term = env::var("TERM").unwrap_or_else(|_| "xterm".into());
+    let mut size = terminal::size().unwrap_or((80, 24));
+    retry(|| channel.request_pty(&term, None, Some((size.0.into(), size.1.into(), 0, 0))))?;
+    match dir {
+        Some(dir) => {
+            let command = format!("cd {} && exec \"${{SHELL:-/bin/sh}}\" -l", shell_quote(dir));
+            debug!("running remote command `{}` in a pty", command);
+            retry(|| channel.exec(&command))?
+        },
+        None => retry(|| channel.shell())?,
+    }
+
+    let waiter = SessionWaiter::new(session)?;
+    // stdin has no non-blocking read, so it's read on its own thread, which wakes the loop below
+    let (sender, receiver) = mpsc::channel::<Vec<u8>>();
+    let waker = waiter.waker();
+    thread::spawn(move || {
+        let mut stdin = io::stdin();
+        let mut buf = [0_u8; 1024];
+        while let Ok(amount) = stdin.read(&mut buf) {
+            if amount == 0 || sender.send(buf[..amount].to_vec()).is_err() {
+                break;
+            }
+            waker.wake();
+        }
+        drop(sender);
+        waker.wake();
+    });
+
+    let _raw_mode = RawMode::enable()?;
+    let mut stdout = io::stdout();
+    let mut buf = [0_u8; 16384];
+    let mut pending: Vec<u8> = Vec::new();
+    let mut stdin_open = true;
+    let mut last_resize = Instant::now();
+    loop {
+        let mut progressed = false;
+
+        match channel.read(&mut buf) {
+            Ok(0) => {},
+            Ok(amount) => {
+                stdout.write_all(&buf[..amount])?;
+                stdout.flush()?;
+                progressed = true;
+            },
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {},
+            Err(e) => return Err(e.into()),
+        }
+        if channel.eof() {
+            break;
+        }
+
+        if pending.is_empty() && stdin_open {
+            match receiver.try_recv() {
+                Ok(input) => pending = input,
+                Err(TryRecvError::Empty) => {},
+                Err(TryRecvError::Disconnected) => {
+                    stdin_open = false;
+                    let _ = retry(|| channel.send_eof());
+                },
+            }
+        }
+        if !pending.is_empty() {
+            match channel.write(&pending) {
+                Ok(amount) => {
+                    pending.drain(..amount);
+                    progressed = true;
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {},
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        if last_resize.elapsed() >= RESIZE_INTERVAL {
+            last_resize = Instant::now();
+            if let Ok(new_size) = terminal::size() {
+                if new_size != size {
+                    size = new_size;
+                    retry(|| channel.request_pty_size(size.0.into(), size.1.into(), None, None))?;
+                }
+            }
+        }
+
+        if !progressed {
+            // the terminal size is checked whenever the loop wakes up, and at least every RESIZE_INTERVAL
+            waiter.wait(RESIZE_INTERVAL)?;
+        }
+    }
+    retry(|| channel.wait_close())?;
+    exit_code(&channel)
+}
@@ -0,0 +1,454 @@
+use log::debug;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+use ureq::{Agent, AgentBuilder, Request};
+
+use crate::st::error::Error;
+
+/// Timeout for regular requests. Event requests wait for their own timeout on top of this.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Client for the syncthing REST API at `base_url`, e.g. `http://127.0.0.1:8384`.
+#[derive(Clone, Debug)]
+pub struct Client {
+    base_url: String,
+    api_key: String,
+    agent: Agent,
+}
+
+/// `/rest/system/status`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SystemStatus {
+    #[serde(rename = "myID")]
+    pub my_id: String,
+    pub uptime: u64,
+    pub start_time: String,
+    #[serde(default)]
+    pub alloc: u64,
+    #[serde(default)]
+    pub goroutines: u64,
+}
+
+/// `/rest/db/completion`, for a folder, a device or everything.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Completion {
+    /// Percentage, 100 when nothing is needed
+    pub completion: f64,
+    pub global_bytes: u64,
+    pub need_bytes: u64,
+    pub global_items: u64,
+    pub need_items: u64,
+    pub need_deletes: u64,
+    #[serde(default)]
+    pub remote_state: Option<String>,
+    pub sequence: u64,
+}
+
+/// `/rest/db/status`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderStatus {
+    /// `idle`, `scanning`, `syncing`, `error`, ...
+    pub state: String,
+    pub state_changed: String,
+    #[serde(default)]
+    pub error: String,
+    pub errors: u64,
+    pub pull_errors: u64,
+    pub global_bytes: u64,
+    pub global_files: u64,
+    pub local_bytes: u64,
+    pub local_files: u64,
+    pub need_bytes: u64,
+    pub need_files: u64,
+    pub need_deletes: u64,
+    pub in_sync_bytes: u64,
+    pub in_sync_files: u64,
+    pub sequence: u64,
+}
+
+/// An entry from `/rest/events`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Event {
+    pub id: u64,
+    #[serde(rename = "globalID")]
+    pub global_id: u64,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub time: String,
+    /// Depends on the event type
+    #[serde(default)]
+    pub data: serde_json::Value,
+}
+
+/// `/rest/system/connections`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Connections {
+    pub total: ConnectionTotals,
+    /// By device ID
+    pub connections: HashMap<String, Connection>,
+}
+
+/// Traffic over all connections.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionTotals {
+    pub at: String,
+    pub in_bytes_total: u64,
+    pub out_bytes_total: u64,
+}
+
+/// Connection state of one device.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Connection {
+    pub connected: bool,
+    pub paused: bool,
+    pub address: String,
+    pub client_version: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub at: String,
+    pub in_bytes_total: u64,
+    pub out_bytes_total: u64,
+}
+
+impl Client {
+    pub fn new(base_url: &str, api_key: &str) -> Client {
+        Client {
+            base_url: base_url.trim_end_matches('/').into(),
+            api_key: api_key.into(),
+            agent: AgentBuilder::new()
+                .timeout_connect(Duration::from_secs(5))
+                .timeout_read(REQUEST_TIMEOUT)
+                .build(),
+        }
+    }
+
+    pub fn base_url(&self) -> &str { &self.base_url }
+
+    /// `GET /rest/system/status`
+    pub fn system_status(&self) -> Result<SystemStatus, Error> {
+        self.get_json(self.request("GET", "/rest/system/status"))
+    }
+
+    /// `GET /rest/db/completion`. Without a folder it covers all folders, without a device it's the local
+    /// device's completion, i.e. how much of the global state this side has.
+    pub fn completion(&self, folder: Option<&str>, device: Option<&str>) -> Result<Completion, Error> {
+        let mut request = self.request("GET", "/rest/db/completion");
+        if let Some(folder) = folder {
+            request = request.query("folder", folder);
+        }
+        if let Some(device) = device {
+            request = request.query("device", device);
+        }
+        self.get_json(request)
+    }
+
+    /// `GET /rest/db/status`
+    pub fn folder_status(&self, folder: &str) -> Result<FolderStatus, Error> {
+        self.get_json(self.request("GET", "/rest/db/status").query("folder", folder))
+    }
+
+    /// `GET /rest/events`, returning events after `since`. Blocks for up to `timeout` until there is one.
+    /// `events` filters by type, e.g. `["FolderSummary", "StateChanged"]`.
+    pub fn events(&self, since: u64, events: &[&str], timeout: Duration) -> Result<Vec<Event>, Error> {
+        let mut request = self
+            .request("GET", "/rest/events")
+            .query("since", &since.to_string())
+            .query("timeout", &timeout.as_secs().to_string())
+            .timeout(timeout + REQUEST_TIMEOUT);
+        if !events.is_empty() {
+            request = request.query("events", &events.join(","));
+        }
+        self.get_json(request)
+    }
+
+    /// `GET /rest/system/connections`
+    pub fn connections(&self) -> Result<Connections, Error> {
+        self.get_json(self.request("GET", "/rest/system/connections"))
+    }
+
+    /// `POST /rest/db/scan`, rescanning a folder or only `sub` inside it. syncthing answers once the scan
+    /// is done, which can take longer than regular requests, so it's given up on after `timeout`.
+    pub fn scan(&self, folder: &str, sub: Option<&str>, timeout: Duration) -> Result<(), Error> {
+        let mut request = self
+            .request("POST", "/rest/db/scan")
+            .query("folder", folder)
+            .timeout(timeout);
+        if let Some(sub) = sub {
+            request = request.query("sub", sub);
+        }
+        self.post(request)
+    }
+
+    /// `POST /rest/system/pause`, for one device or all of them.
+    pub fn pause(&self, device: Option<&str>) -> Result<(), Error> {
+        let mut request = self.request("POST", "/rest/system/pause");
+        if let Some(device) = device {
+            request = request.query("device", device);
+        }
+        self.post(request)
+    }
+
+    /// `POST /rest/system/resume`, for one device or all of them.
+    pub fn resume(&self, device: Option<&str>) -> Result<(), Error> {
+        let mut request = self.request("POST", "/rest/system/resume");
+        if let Some(device) = device {
+            request = request.query("device", device);
+        }
+        self.post(request)
+    }
+
+    fn request(&self, method: &str, path: &str) -> Request {
+        debug!("{} {}{}", method, self.base_url, path);
+        self.agent
+            .request(method, &format!("{}{}", self.base_url, path))
+            .set("X-API-Key", &self.api_key)
+    }
+
+    fn get_json<T: DeserializeOwned>(&self, request: Request) -> Result<T, Error> {
+        let url = request.url().to_string();
+        let response = request.call().map_err(|e| api_error(&url, e))?;
+        response.into_json().map_err(|e| Error::Api {
+            url,
+            message: e.to_string(),
+        })
+    }
+
+    fn post(&self, request: Request) -> Result<(), Error> {
+        let url = request.url().to_string();
+        request.call().map_err(|e| api_error(&url, e))?;
+        Ok(())
+    }
+}
+
+/// Include the response body for HTTP errors, syncthing explains what's wrong there.
+fn api_error(url: &str, error: ureq::Error) -> Error {
+    let message = match error {
+        ureq::Error::Status(code, response) => {
+            let body = response.into_string().unwrap_or_default();
+            format!("HTTP {}: {}", code, body.trim())
+        },
+        ureq::Error::Transport(transport) => transport.to_string(),
+    };
+    Error::Api {
+        url: url.into(),
+        message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::mpsc::{channel, Receiver},
+        thread,
+    };
+
+    use super::*;
+
+    const API_KEY: &str = "test-api-key";
+
+    /// Request line and headers of a request the mock server received.
+    struct Received {
+        request_line: String,
+        headers: Vec<String>,
+    }
+
+    impl Received {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers.iter().find_map(|x| {
+                let (key, value) = x.split_once(':')?;
+                match key.eq_ignore_ascii_case(name) {
+                    true => Some(value.trim()),
+                    false => None,
+                }
+            })
+        }
+    }
+
+    /// Serve one canned response per connection, in order, on a free loopback port.
+    fn serve(responses: Vec<(u16, &'static str)>) -> (Client, Receiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = Client::new(&format!("http://{}/", listener.local_addr().unwrap()), API_KEY);
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            for (status, body) in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut headers = Vec::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    headers.push(line.trim().to_string());
+                }
+                let mut stream = stream;
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Status\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: \
+                     close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+                sender
+                    .send(Received {
+                        request_line: request_line.trim().to_string(),
+                        headers,
+                    })
+                    .unwrap();
+            }
+        });
+        (client, receiver)
+    }
+
+    #[test]
+    fn system_status() {
+        let (client, received) = serve(vec![(
+            200,
+            r#"{"myID":"AAAAAAA-BBBBBBB","uptime":42,"startTime":"2022-10-01T12:00:00Z","alloc":1024,"goroutines":7}"#,
+        )]);
+        let status = client.system_status().unwrap();
+        assert_eq!(status.my_id, "AAAAAAA-BBBBBBB");
+        assert_eq!(status.uptime, 42);
+        assert_eq!(status.goroutines, 7);
+
+        let request = received.recv().unwrap();
+        assert_eq!(request.request_line, "GET /rest/system/status HTTP/1.1");
+        assert_eq!(request.header("X-API-Key"), Some(API_KEY));
+    }
+
+    #[test]
+    fn completion() {
+        let body = r#"{"completion":99.5,"globalBytes":200,"needBytes":1,"globalItems":10,"needItems":1,"needDeletes":0,"remoteState":"valid","sequence":12}"#;
+        let (client, received) = serve(vec![(200, body), (200, body)]);
+        let completion = client.completion(Some("abcd-1234"), Some("DEVICE")).unwrap();
+        assert_eq!(completion.completion, 99.5);
+        assert_eq!(completion.need_bytes, 1);
+        assert_eq!(completion.remote_state.as_deref(), Some("valid"));
+        let request = received.recv().unwrap();
+        assert_eq!(
+            request.request_line,
+            "GET /rest/db/completion?folder=abcd-1234&device=DEVICE HTTP/1.1"
+        );
+        assert_eq!(request.header("X-API-Key"), Some(API_KEY));
+
+        client.completion(None, None).unwrap();
+        assert_eq!(
+            received.recv().unwrap().request_line,
+            "GET /rest/db/completion HTTP/1.1"
+        );
+    }
+
+    #[test]
+    fn folder_status() {
+        let (client, received) = serve(vec![(
+            200,
+            r#"{"state":"syncing","stateChanged":"2022-10-01T12:00:00Z","errors":0,"pullErrors":0,"globalBytes":300,"globalFiles":3,"localBytes":100,"localFiles":1,"needBytes":200,"needFiles":2,"needDeletes":0,"inSyncBytes":100,"inSyncFiles":1,"sequence":5}"#,
+        )]);
+        let status = client.folder_status("abcd-1234").unwrap();
+        assert_eq!(status.state, "syncing");
+        assert_eq!(status.need_files, 2);
+        assert_eq!(status.error, "");
+        let request = received.recv().unwrap();
+        assert_eq!(request.request_line, "GET /rest/db/status?folder=abcd-1234 HTTP/1.1");
+        assert_eq!(request.header("X-API-Key"), Some(API_KEY));
+    }
+
+    #[test]
+    fn events() {
+        let (client, received) = serve(vec![(
+            200,
+            r#"[{"id":8,"globalID":120,"type":"StateChanged","time":"2022-10-01T12:00:00Z","data":{"folder":"abcd-1234","to":"idle"}}]"#,
+        )]);
+        let events = client
+            .events(7, &["StateChanged", "FolderSummary"], Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].id, 8);
+        assert_eq!(events[0].global_id, 120);
+        assert_eq!(events[0].kind, "StateChanged");
+        assert_eq!(events[0].data["to"], "idle");
+        let request = received.recv().unwrap();
+        assert_eq!(
+            request.request_line,
+            "GET /rest/events?since=7&timeout=1&events=StateChanged%2CFolderSummary HTTP/1.1"
+        );
+        assert_eq!(request.header("X-API-Key"), Some(API_KEY));
+    }
+
+    #[test]
+    fn connections() {
+        let (client, received) = serve(vec![(
+            200,
+            r#"{"total":{"at":"2022-10-01T12:00:00Z","inBytesTotal":10,"outBytesTotal":20},"connections":{"DEVICE":{"connected":true,"paused":false,"address":"127.0.0.1:22001","clientVersion":"v1.21.0","type":"tcp-client","at":"2022-10-01T12:00:00Z","inBytesTotal":10,"outBytesTotal":20}}}"#,
+        )]);
+        let connections = client.connections().unwrap();
+        assert_eq!(connections.total.out_bytes_total, 20);
+        let device = &connections.connections["DEVICE"];
+        assert!(device.connected);
+        assert_eq!(device.kind, "tcp-client");
+        let request = received.recv().unwrap();
+        assert_eq!(request.request_line, "GET /rest/system/connections HTTP/1.1");
+        assert_eq!(request.header("X-API-Key"), Some(API_KEY));
+    }
+
+    #[test]
+    fn posts() {
+        let (client, received) = serve(vec![(200, ""), (200, ""), (200, ""), (200, "")]);
+        client
+            .scan("abcd-1234", Some("src/main.rs"), Duration::from_secs(5))
+            .unwrap();
+        let request = received.recv().unwrap();
+        assert_eq!(
+            request.request_line,
+            "POST /rest/db/scan?folder=abcd-1234&sub=src%2Fmain.rs HTTP/1.1"
+        );
+        assert_eq!(request.header("X-API-Key"), Some(API_KEY));
+
+        client.pause(Some("DEVICE")).unwrap();
+        assert_eq!(
+            received.recv().unwrap().request_line,
+            "POST /rest/system/pause?device=DEVICE HTTP/1.1"
+        );
+        client.resume(None).unwrap();
+        assert_eq!(
+            received.recv().unwrap().request_line,
+            "POST /rest/system/resume HTTP/1.1"
+        );
+        client.scan("abcd-1234", None, Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            received.recv().unwrap().request_line,
+            "POST /rest/db/scan?folder=abcd-1234 HTTP/1.1"
+        );
+    }
+
+    #[test]
+    fn http_error() {
+        let (client, _received) = serve(vec![(404, "no such folder\n")]);
+        match client.folder_status("missing") {
+            Err(Error::Api { url, message }) => {
+                assert!(url.ends_with("/rest/db/status?folder=missing"), "{}", url);
+                assert_eq!(message, "HTTP 404: no such folder");
+            },
+            other => panic!("expected an API error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid_json() {
+        let (client, _received) = serve(vec![(200, "not json")]);
+        assert!(matches!(client.system_status(), Err(Error::Api { .. })));
+    }
+}
@@ -0,0 +1,213 @@
+use flate2::read::GzDecoder;
+use std::{
+    env, fmt, fs,
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+};
+use tar::Archive;
+
+use sha2::{Digest, Sha256};
+
+use crate::st::error::Error;
+
+/// Version of the bundled syncthing binaries.
+pub const SYNCTHING_VERSION: &str = "v1.21.0";
+
+/// Compressed syncthing binaries shipped inside stw, by platform.
+const BUNDLED: &[(&str, &[u8])] = &[(
+    "linux-amd64",
+    include_bytes!("../../resources/syncthing-linux-amd64-v1.21.0.xz"),
+)];
+
+/// `sha256sum` output for the decompressed bundled binaries.
+const MANIFEST: &str = include_str!("../../resources/SHA256SUMS");
+
+/// OS and architecture, named the way syncthing names its release builds.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Platform {
+    pub os: String,
+    pub arch: String,
+}
+
+/// `uname -s` output and the matching syncthing OS name.
+const OPERATING_SYSTEMS: [(&str, &str); 8] = [
+    ("linux", "linux"),
+    ("darwin", "macos"),
+    ("freebsd", "freebsd"),
+    ("openbsd", "openbsd"),
+    ("netbsd", "netbsd"),
+    ("dragonfly", "dragonfly"),
+    ("sunos", "solaris"),
+    ("windows", "windows"),
+];
+
+/// Map `uname -m` output (or a Rust target arch) to a syncthing architecture name.
+fn arch_name(arch: &str) -> Option<&'static str> {
+    let arch = match arch.to_lowercase().as_str() {
+        "x86_64" | "amd64" => "amd64",
+        "aarch64" | "arm64" | "aarch64_be" => "arm64",
+        arch if arch.starts_with("armv") || arch == "arm" => "arm",
+        "i386" | "i486" | "i586" | "i686" | "x86" | "386" => "386",
+        "riscv64" => "riscv64",
+        "ppc64le" => "ppc64le",
+        "ppc64" | "powerpc64" => "ppc64",
+        "s390x" => "s390x",
+        "mips" => "mips",
+        "mipsel" | "mipsle" => "mipsle",
+        "mips64" => "mips64",
+        "mips64el" | "mips64le" => "mips64le",
+        _ => return None,
+    };
+    Some(arch)
+}
+
+impl Platform {
+    /// Map the output of `uname -sm` to a syncthing platform.
+    pub fn from_uname(uname: &str) -> Result<Platform, Error> {
+        let unsupported = || Error::UnsupportedPlatform(uname.trim().into());
+        let mut parts = uname.split_whitespace();
+        let os = parts.next().ok_or_else(unsupported)?.to_lowercase();
+        let (_, os) = OPERATING_SYSTEMS
+            .iter()
+            .find(|(uname_os, _)| *uname_os == os)
+            .ok_or_else(unsupported)?;
+        let arch = arch_name(parts.next().ok_or_else(unsupported)?).ok_or_else(unsupported)?;
+        Ok(Platform {
+            os: os.to_string(),
+            arch: arch.into(),
+        })
+    }
+
+    /// Platform stw itself is running on.
+    pub fn local() -> Result<Platform, Error> {
+        let unsupported = || Error::UnsupportedPlatform(format!("{} {}", env::consts::OS, env::consts::ARCH));
+        let (_, os) = OPERATING_SYSTEMS
+            .iter()
+            .find(|(_, os)| *os == env::consts::OS)
+            .ok_or_else(unsupported)?;
+        Ok(Platform {
+            os: os.to_string(),
+            arch: arch_name(env::consts::ARCH).ok_or_else(unsupported)?.into(),
+        })
+    }
+
+    /// Platform from a syncthing release file name such as `syncthing-linux-arm64-v1.23.0.tar.gz`.
+    pub fn from_file_name(name: &str) -> Option<Platform> {
+        let mut parts = name.strip_prefix("syncthing-")?.split('-');
+        let (os, arch) = (parts.next()?, parts.next()?);
+        OPERATING_SYSTEMS.iter().find(|(_, x)| *x == os)?;
+        match arch_name(arch) {
+            Some(name) if name == arch => Some(Platform {
+                os: os.into(),
+                arch: arch.into(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// File name of the syncthing binary for this platform, as it's looked up in the cache folder.
+    pub fn binary_name(&self, version: &str) -> String { format!("syncthing-{}-{}", self, version) }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { write!(f, "{}-{}", self.os, self.arch) }
+}
+
+/// SHA-256 of the bundled binary for `platform` from the manifest, as lowercase hex.
+pub fn bundled_sha256(platform: &Platform) -> Option<String> {
+    let name = platform.binary_name(SYNCTHING_VERSION);
+    MANIFEST.lines().filter(|x| !x.starts_with('#')).find_map(|line| {
+        let mut parts = line.split_whitespace();
+        let hash = parts.next()?;
+        // sha256sum marks binary mode with a leading `*`
+        match parts.next()?.trim_start_matches('*') == name {
+            true => Some(hash.to_lowercase()),
+            false => None,
+        }
+    })
+}
+
+/// SHA-256 of `data` as lowercase hex.
+pub fn sha256_hex(data: &[u8]) -> String { format!("{:x}", Sha256::digest(data)) }
+
+/// Find the syncthing `version` binary for `platform`, either bundled with stw or in `cache_folder` as
+/// `syncthing-<platform>-<version>`, optionally xz compressed. Bundled binaries are checked against
+/// the manifest after decompression.
+pub fn syncthing_binary(platform: &Platform, version: &str, cache_folder: &Path) -> Result<Vec<u8>, Error> {
+    let name = platform.to_string();
+    let bundled = BUNDLED.iter().find(|(x, _)| *x == name);
+    if let (Some((_, compressed)), true) = (bundled, version == SYNCTHING_VERSION) {
+        let binary = decompress(compressed)?;
+        let expected = bundled_sha256(platform).ok_or_else(|| Error::MissingChecksum(platform.binary_name(version)))?;
+        let actual = sha256_hex(&binary);
+        if actual != expected {
+            return Err(Error::Checksum {
+                name: platform.binary_name(version),
+                expected,
+                actual,
+            });
+        }
+        return Ok(binary);
+    }
+
+    let cached = cache_folder.join(platform.binary_name(version));
+    if cached.exists() {
+        return Ok(fs::read(cached)?);
+    }
+    let mut cached_xz = cached.clone().into_os_string();
+    cached_xz.push(".xz");
+    let cached_xz = PathBuf::from(cached_xz);
+    if cached_xz.exists() {
+        return decompress(&fs::read(cached_xz)?);
+    }
+
+    Err(Error::NoBinary {
+        platform: name,
+        path: cached,
+    })
+}
+
+/// Read a syncthing binary from `path`, which may be the binary itself, xz compressed, or a release
+/// `.tar.gz`/`.tgz`/`.tar.xz` archive.
+pub fn extract_binary(path: &Path) -> Result<Vec<u8>, Error> {
+    let name = path
+        .file_name()
+        .map(|x| x.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let data = fs::read(path)?;
+    if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        from_tar(GzDecoder::new(data.as_slice()), path)
+    } else if name.ends_with(".tar.xz") {
+        from_tar(decompress(&data)?.as_slice(), path)
+    } else if name.ends_with(".xz") {
+        decompress(&data)
+    } else {
+        Ok(data)
+    }
+}
+
+/// Find the `syncthing` executable in a release archive, it's under a versioned folder.
+fn from_tar(reader: impl Read, path: &Path) -> Result<Vec<u8>, Error> {
+    let mut archive = Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let is_binary = entry
+            .path()?
+            .file_name()
+            .map(|x| x == "syncthing" || x == "syncthing.exe")
+            .unwrap_or(false);
+        if is_binary {
+            let mut binary = Vec::new();
+            entry.read_to_end(&mut binary)?;
+            return Ok(binary);
+        }
+    }
+    Err(Error::NotInArchive(path.to_path_buf()))
+}
+
+fn decompress(compressed: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut f = Cursor::new(compressed);
+    let mut binary = Vec::new();
+    lzma_rs::xz_decompress(&mut f, &mut binary)?;
+    Ok(binary)
+}
@@ -3,6 +3,13 @@ use bcrypt::{hash, BcryptResult, DEFAULT_COST};
 use rand::{seq::SliceRandom, thread_rng};
 use serde::{Deserialize, Serialize};
 
+/// GUI and REST API port, on both sides.
+pub const DEFAULT_GUI_PORT: u16 = 8384;
+/// Port syncthing listens for the other device on, on both sides.
+pub const DEFAULT_LISTEN_PORT: u16 = 22000;
+/// Port the ssh tunnel listens on, on both sides, for syncthing to reach the other device.
+pub const DEFAULT_TUNNEL_PORT: u16 = 22001;
+
 #[derive(Template, Clone, Debug, Serialize, Deserialize)]
 #[template(path = "config.xml")]
 pub struct ConfigTemplate {
@@ -11,6 +18,14 @@ pub struct ConfigTemplate {
     pub remote_device_id: String,
     pub remote_device_name: String,
     pub gui_password: String,
+    /// REST API key, sent as `X-API-Key`
+    pub api_key: String,
+    #[serde(default = "default_gui_port")]
+    pub gui_port: u16,
+    #[serde(default = "default_listen_port")]
+    pub listen_port: u16,
+    #[serde(default = "default_tunnel_port")]
+    pub tunnel_port: u16,
     pub folders: Vec<Folder>,
 }
 
@@ -20,6 +35,13 @@ pub struct Folder {
     pub path: String,
 }
 
+// configs saved before the ports were configurable used the defaults
+fn default_gui_port() -> u16 { DEFAULT_GUI_PORT }
+
+fn default_listen_port() -> u16 { DEFAULT_LISTEN_PORT }
+
+fn default_tunnel_port() -> u16 { DEFAULT_TUNNEL_PORT }
+
 pub fn generate_password() -> BcryptResult<(String, String)> {
     let charset: Vec<u8> = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789!@#$%^&*_-+="
         .as_bytes()
@@ -31,3 +53,14 @@ pub fn generate_password() -> BcryptResult<(String, String)> {
     let hash = hash(&pass, DEFAULT_COST)?;
     Ok((pass, hash))
 }
+
+/// Random REST API key, in the same format syncthing generates.
+pub fn generate_api_key() -> String {
+    let charset: Vec<u8> = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789"
+        .as_bytes()
+        .to_owned();
+    let mut rng = thread_rng();
+    (0..32)
+        .map(|_| charset.choose(&mut rng).unwrap().to_owned() as char)
+        .collect()
+}
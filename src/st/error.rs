@@ -12,4 +12,29 @@ pub enum Error {
     /// non-base32 character in string
     #[error("Invalid character")]
     Codepoint,
+    /// xz decompression error wrapper
+    #[error("Couldn't decompress syncthing binary")]
+    Xz(#[from] lzma_rs::error::Error),
+    /// binary doesn't match its SHA-256
+    #[error("Checksum mismatch for {name}, expected {expected} but got {actual}")]
+    Checksum {
+        name: String,
+        expected: String,
+        actual: String,
+    },
+    /// bundled binary without a manifest entry
+    #[error("No checksum for {0} in the manifest")]
+    MissingChecksum(String),
+    /// archive without a syncthing binary
+    #[error("No syncthing binary in {0:#?}")]
+    NotInArchive(std::path::PathBuf),
+    /// `uname` output that doesn't map to a syncthing build
+    #[error("Unsupported platform `{0}`")]
+    UnsupportedPlatform(String),
+    /// no bundled or cached syncthing binary for the platform
+    #[error("No syncthing binary for {platform}, download it to {path:#?}")]
+    NoBinary { platform: String, path: std::path::PathBuf },
+    /// failed REST API request
+    #[error("syncthing API request to {url} failed: {message}")]
+    Api { url: String, message: String },
 }
@@ -24,7 +24,7 @@ pub fn luhn32(string: &str) -> Result<char, Error> {
 /// translated from main/lib/protocol/luhn.go
 pub fn codepoint(c: &char) -> Result<u32, Error> {
     match *c {
-        char if ('A'..='Z').contains(&char) => Ok(char as u32 - 'A' as u32),
+        char if char.is_ascii_uppercase() => Ok(char as u32 - 'A' as u32),
         char if ('2'..='7').contains(&char) => Ok(char as u32 + 26 - '2' as u32),
         _ => Err(Error::Codepoint),
     }
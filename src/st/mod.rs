@@ -1,3 +1,7 @@
+/// syncthing REST API client
+pub mod api;
+/// syncthing binaries for each platform
+pub mod binary;
 /// support for syncthing config file
 pub mod config;
 /// translated from ST main/lib/protocol/deviceid.go
@@ -6,3 +10,5 @@ pub mod deviceid;
 pub mod error;
 /// translated from main/lib/protocol/luhn.go
 pub mod luhn;
+/// local syncthing process supervision
+pub mod process;
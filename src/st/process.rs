@@ -0,0 +1,215 @@
+use log::{debug, error, info, warn};
+use std::{
+    env,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::st::error::Error;
+
+/// syncthing exits with 3 when it wants to be restarted, e.g. after an upgrade or a GUI restart.
+const RESTART_EXIT_CODE: i32 = 3;
+/// Longest wait between restarts of a crashing syncthing.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A run this long resets the restart backoff.
+const HEALTHY_RUN: Duration = Duration::from_secs(60);
+/// How long syncthing gets to shut down before it's killed.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Find `syncthing` in `PATH`.
+pub fn find_in_path() -> Option<PathBuf> {
+    let name = match cfg!(windows) {
+        true => "syncthing.exe",
+        false => "syncthing",
+    };
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// Runs the local syncthing instance, restarting it when it crashes. Its output goes to
+/// `syncthing.log` in the home folder and to the debug log.
+pub struct Supervisor {
+    child: Arc<Mutex<Option<Child>>>,
+    stopping: Arc<AtomicBool>,
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Supervisor {
+    /// Start `binary serve --home=<home>` and keep it running until `stop` is called.
+    pub fn start(binary: &Path, home: &Path) -> Result<Supervisor, Error> {
+        let mut command = Command::new(binary);
+        command
+            .arg("serve")
+            .arg(format!("--home={}", home.to_string_lossy()))
+            // restarts are handled here instead of by syncthing's monitor process
+            .args(["--no-browser", "--no-restart"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let log_path = home.join("syncthing.log");
+
+        // spawn the first instance here so a bad binary is reported to the caller
+        let child = spawn(&mut command, &log_path)?;
+        info!("Local syncthing started (pid {})", child.id());
+        let child = Arc::new(Mutex::new(Some(child)));
+        let stopping = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let child = child.clone();
+            let stopping = stopping.clone();
+            thread::spawn(move || supervise(command, log_path, child, stopping))
+        };
+        Ok(Supervisor {
+            child,
+            stopping,
+            handle: Mutex::new(Some(handle)),
+        })
+    }
+
+    /// Stop syncthing, giving it time to shut down cleanly. Safe to call more than once.
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        if let Some(mut child) = self.child.lock().unwrap().take() {
+            debug!("stopping local syncthing (pid {})", child.id());
+            if let Err(e) = terminate(&mut child) {
+                warn!("Couldn't stop local syncthing: {}", e);
+            }
+        }
+        if let Some(handle) = self.handle.lock().unwrap().take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Wait for syncthing to exit and start it again, backing off while it keeps crashing.
+fn supervise(mut command: Command, log_path: PathBuf, child: Arc<Mutex<Option<Child>>>, stopping: Arc<AtomicBool>) {
+    let mut backoff = Duration::from_secs(1);
+    let mut started = Instant::now();
+    loop {
+        let status = match wait(&child, &stopping) {
+            Some(status) => status,
+            None => return,
+        };
+        match status.code() {
+            Some(0) => {
+                info!("Local syncthing exited");
+                return;
+            },
+            Some(RESTART_EXIT_CODE) => info!("Local syncthing asked to be restarted"),
+            _ => {
+                if started.elapsed() >= HEALTHY_RUN {
+                    backoff = Duration::from_secs(1);
+                }
+                warn!(
+                    "Local syncthing exited with {}, restarting in {}s",
+                    status,
+                    backoff.as_secs()
+                );
+                let deadline = Instant::now() + backoff;
+                while Instant::now() < deadline && !stopping.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(200));
+                }
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            },
+        }
+
+        // hold the lock so `stop` can't miss the new process
+        let mut slot = child.lock().unwrap();
+        if stopping.load(Ordering::SeqCst) {
+            return;
+        }
+        match spawn(&mut command, &log_path) {
+            Ok(new) => {
+                info!("Local syncthing restarted (pid {})", new.id());
+                *slot = Some(new);
+                started = Instant::now();
+            },
+            Err(e) => {
+                error!("Couldn't restart local syncthing: {}", e);
+                return;
+            },
+        }
+    }
+}
+
+/// Poll the child until it exits. Returns `None` once `stop` has taken it over.
+fn wait(child: &Mutex<Option<Child>>, stopping: &AtomicBool) -> Option<ExitStatus> {
+    loop {
+        {
+            let mut slot = child.lock().unwrap();
+            let current = slot.as_mut()?;
+            match current.try_wait() {
+                Ok(Some(status)) => {
+                    slot.take();
+                    return Some(status);
+                },
+                Ok(None) => {},
+                Err(e) => {
+                    error!("Couldn't check on local syncthing: {}", e);
+                    return None;
+                },
+            }
+        }
+        if stopping.load(Ordering::SeqCst) {
+            return None;
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+fn spawn(command: &mut Command, log_path: &Path) -> Result<Child, Error> {
+    let log = Arc::new(Mutex::new(OpenOptions::new().create(true).append(true).open(log_path)?));
+    let mut child = command.spawn()?;
+    if let Some(stdout) = child.stdout.take() {
+        capture(stdout, log.clone());
+    }
+    if let Some(stderr) = child.stderr.take() {
+        capture(stderr, log);
+    }
+    Ok(child)
+}
+
+/// Copy syncthing's output to the log file and the debug log, line by line.
+fn capture(output: impl Read + Send + 'static, log: Arc<Mutex<File>>) {
+    thread::spawn(move || {
+        for line in BufReader::new(output).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            debug!(target: "syncthing", "{}", line);
+            let _ = writeln!(log.lock().unwrap(), "{}", line);
+        }
+    });
+}
+
+/// Ask syncthing to shut down with SIGTERM, and kill it if it doesn't within `SHUTDOWN_TIMEOUT`.
+fn terminate(child: &mut Child) -> Result<(), Error> {
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    {
+        // SAFETY: kill has no memory safety requirements, the pid belongs to our unreaped child
+        if unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) } == 0 {
+            let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+            while Instant::now() < deadline {
+                if child.try_wait()?.is_some() {
+                    return Ok(());
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+            warn!("Local syncthing didn't shut down, killing it");
+        }
+    }
+    child.kill()?;
+    child.wait()?;
+    Ok(())
+}